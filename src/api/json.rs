@@ -0,0 +1,311 @@
+use crate::blockchain::spv::{TxProof, UtxoProof};
+use crate::blockchain::Blockchain;
+use crate::types::block::{Block, Header};
+use crate::types::hash::Hashable;
+use crate::types::transaction::SignedTransaction;
+use serde::Serialize;
+
+// JSON views of the chain types returned by the API,
+// hashes, addresses, keys and signatures are hex strings rather than byte arrays
+
+#[derive(Serialize)]
+pub struct HeaderJson {
+    pub hash: String,
+    pub parent: String,
+    pub nonce: u32,
+    pub difficulty: String,
+    pub timestamp: u128,
+    pub merkle_root: String,
+    pub state_root: String,
+}
+
+#[derive(Serialize)]
+pub struct TxInJson {
+    pub previous_output: String,
+    pub index: u8,
+}
+
+#[derive(Serialize)]
+pub struct TxOutJson {
+    pub recipient: String,
+    pub value: u64,
+}
+
+#[derive(Serialize)]
+pub struct TransactionJson {
+    pub hash: String,
+    pub coinbase: bool,
+    pub inputs: Vec<TxInJson>,
+    pub outputs: Vec<TxOutJson>,
+    pub public_key: String,
+    pub signature: String,
+}
+
+//////
+/// BlockJson is a decoded block
+/// confirmations: blocks of the longest chain from the block up to the tip, 0 if not in it
+//////
+#[derive(Serialize)]
+pub struct BlockJson {
+    pub height: u128,
+    pub in_longest_chain: bool,
+    pub confirmations: u128,
+    pub size: u64,
+    pub header: HeaderJson,
+    pub transactions: Vec<TransactionJson>,
+}
+
+//////
+/// TxLookupJson is a transaction with the block containing it,
+/// block is None for a transaction only in the mempool
+//////
+#[derive(Serialize)]
+pub struct TxLookupJson {
+    pub transaction: TransactionJson,
+    pub block: Option<String>,
+    pub height: Option<u128>,
+    pub confirmations: u128,
+}
+
+impl From<&Header> for HeaderJson {
+    fn from(header: &Header) -> Self {
+        HeaderJson {
+            hash: header.hash().to_string(),
+            parent: header.parent.to_string(),
+            nonce: header.nonce,
+            difficulty: header.difficulty.to_string(),
+            timestamp: header.timestamp,
+            merkle_root: header.merkle_root.to_string(),
+            state_root: header.state_root.to_string(),
+        }
+    }
+}
+
+impl From<&SignedTransaction> for TransactionJson {
+    fn from(tx: &SignedTransaction) -> Self {
+        TransactionJson {
+            hash: tx.hash().to_string(),
+            coinbase: tx.is_coinbase(),
+            inputs: tx
+                .transaction
+                .tx_input
+                .iter()
+                .map(|tx_in| TxInJson {
+                    previous_output: tx_in.previous_output.to_string(),
+                    index: tx_in.index,
+                })
+                .collect(),
+            outputs: tx
+                .transaction
+                .tx_output
+                .iter()
+                .map(|tx_out| TxOutJson {
+                    recipient: tx_out.recipient_addr.to_string(),
+                    value: tx_out.value,
+                })
+                .collect(),
+            public_key: hex::encode(&tx.public_key),
+            signature: hex::encode(&tx.signature),
+        }
+    }
+}
+
+impl BlockJson {
+    pub fn new(block: &Block, height: u128, confirmations: Option<u128>) -> Self {
+        BlockJson {
+            height,
+            in_longest_chain: confirmations.is_some(),
+            confirmations: confirmations.unwrap_or(0),
+            size: block.size(),
+            header: HeaderJson::from(&block.header),
+            transactions: block
+                .content
+                .data
+                .iter()
+                .map(TransactionJson::from)
+                .collect(),
+        }
+    }
+}
+
+//////
+/// BlockTreeNodeJson is a block of the block tree
+/// miner: recipient of the coinbase, None for genesis
+/// main_chain: the block is in the longest chain
+//////
+#[derive(Serialize)]
+pub struct BlockTreeNodeJson {
+    pub hash: String,
+    pub parent: String,
+    pub height: u128,
+    pub miner: Option<String>,
+    pub tx_count: usize,
+    pub main_chain: bool,
+}
+
+impl BlockTreeNodeJson {
+    pub fn new(block: &Block, height: u128, main_chain: bool) -> Self {
+        let miner = block
+            .content
+            .data
+            .first()
+            .filter(|tx| tx.is_coinbase())
+            .and_then(|coinbase| coinbase.transaction.tx_output.first())
+            .map(|tx_out| tx_out.recipient_addr.to_string());
+        BlockTreeNodeJson {
+            hash: block.hash().to_string(),
+            parent: block.header.parent.to_string(),
+            height,
+            miner,
+            tx_count: block.content.data.len(),
+            main_chain,
+        }
+    }
+}
+
+//////
+/// StatsJson is the fork and orphan statistics of the node
+/// total_blocks: blocks in the block tree, main_chain_blocks plus stale_blocks
+/// mean_block_interval: milliseconds between two blocks of the longest chain, None below two blocks
+//////
+#[derive(Serialize)]
+pub struct StatsJson {
+    pub total_blocks: usize,
+    pub main_chain_blocks: u128,
+    pub stale_blocks: u128,
+    pub reorgs: u64,
+    pub max_reorg_depth: usize,
+    pub orphan_arrivals: u64,
+    pub mean_block_interval: Option<f64>,
+    pub store_error: Option<String>,
+}
+
+impl From<&Blockchain> for StatsJson {
+    fn from(blockchain: &Blockchain) -> Self {
+        StatsJson {
+            total_blocks: blockchain.blockchain.len(),
+            main_chain_blocks: blockchain.main_chain_blocks(),
+            stale_blocks: blockchain.stale_blocks(),
+            reorgs: blockchain.stats.reorgs,
+            max_reorg_depth: blockchain.stats.max_reorg_depth,
+            orphan_arrivals: blockchain.stats.orphan_arrivals,
+            mean_block_interval: blockchain.mean_block_interval(),
+            store_error: blockchain.store_error.clone(),
+        }
+    }
+}
+
+//////
+/// FinalityJson tells if a block, or the block containing a transaction, is final
+/// confirmations: blocks of the longest chain from the block up to the tip, 0 if not in it
+//////
+#[derive(Serialize)]
+pub struct FinalityJson {
+    pub block: String,
+    pub height: u128,
+    pub in_longest_chain: bool,
+    pub confirmations: u128,
+    pub finalized: bool,
+    pub finalized_height: u128,
+}
+
+#[derive(Serialize)]
+pub struct BalanceJson {
+    pub address: String,
+    pub balance: u64,
+    pub utxo_count: usize,
+}
+
+#[derive(Serialize)]
+pub struct UtxoJson {
+    pub tx_hash: String,
+    pub index: u8,
+    pub value: u64,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntryJson {
+    pub tx_hash: String,
+    pub block: String,
+    pub height: u128,
+}
+
+//////
+/// PageJson is one page of a list, total is the length of the whole list
+//////
+#[derive(Serialize)]
+pub struct PageJson<T> {
+    pub address: String,
+    pub total: usize,
+    pub offset: usize,
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize)]
+pub struct TxProofJson {
+    pub header: HeaderJson,
+    pub tx_hash: String,
+    pub index: usize,
+    pub leaf_size: usize,
+    pub branch: Vec<String>,
+}
+
+impl From<&TxProof> for TxProofJson {
+    fn from(proof: &TxProof) -> Self {
+        TxProofJson {
+            header: HeaderJson::from(&proof.header),
+            tx_hash: proof.tx_hash.to_string(),
+            index: proof.index,
+            leaf_size: proof.leaf_size,
+            branch: proof.branch.iter().map(|hash| hash.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UtxoProofJson {
+    pub header: HeaderJson,
+    pub tx_hash: String,
+    pub index: u8,
+    pub value: u64,
+    pub recipient: String,
+    pub coinbase_height: Option<u128>,
+    pub position: usize,
+    pub leaf_size: usize,
+    pub branch: Vec<String>,
+}
+
+impl From<&UtxoProof> for UtxoProofJson {
+    fn from(proof: &UtxoProof) -> Self {
+        UtxoProofJson {
+            header: HeaderJson::from(&proof.header),
+            tx_hash: proof.entry.key.0.to_string(),
+            index: proof.entry.key.1,
+            value: proof.entry.value.0,
+            recipient: proof.entry.value.1.to_string(),
+            coinbase_height: proof.entry.coinbase_height,
+            position: proof.index,
+            leaf_size: proof.leaf_size,
+            branch: proof.branch.iter().map(|hash| hash.to_string()).collect(),
+        }
+    }
+}
+
+//////
+/// WatchedTxJson is a tx of an address watched by a light node
+/// proven: a merkle proof of the tx was checked against the best header chain
+/// confirmations: blocks of the best header chain from the tx's block up, 0 if not proven
+//////
+#[derive(Serialize)]
+pub struct WatchedTxJson {
+    pub tx_hash: String,
+    pub proven: bool,
+    pub block: Option<String>,
+    pub confirmations: u128,
+}
+
+#[derive(Serialize)]
+pub struct WatchedAddressJson {
+    pub address: String,
+    pub txs: Vec<WatchedTxJson>,
+}
@@ -1,18 +1,27 @@
+mod json;
+
+use crate::blockchain::spv::UtxoProof;
 use crate::blockchain::Blockchain;
 use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::sync::SyncState;
 use crate::tx_generator::{self, Handle as TxGeneratorHandle};
 use crate::types::address::Address;
 use crate::types::hash::{Hashable, H256};
 use crate::types::state::State;
 use crate::BlockToStateMap;
+use json::{
+    BalanceJson, BlockJson, BlockTreeNodeJson, FinalityJson, HistoryEntryJson, PageJson, StatsJson,
+    TransactionJson, TxLookupJson, TxProofJson, UtxoJson, UtxoProofJson, WatchedAddressJson,
+    WatchedTxJson,
+};
 
 use serde::Serialize;
 
 use log::info;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
@@ -29,6 +38,7 @@ pub struct Server {
     tx_mempool: Arc<Mutex<Mempool>>,
     state: Arc<Mutex<State>>,
     bts_map: Arc<Mutex<BlockToStateMap>>,
+    sync: Arc<Mutex<SyncState>>,
 }
 
 #[derive(Serialize)]
@@ -37,6 +47,71 @@ struct ApiResponse {
     message: String,
 }
 
+// page size of the list endpoints when no limit is given, and the largest allowed
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+// read the address and the offset and limit of a page from the query
+fn address_page_params(
+    params: &HashMap<String, String>,
+) -> Result<(Address, usize, usize), String> {
+    let address = match params.get("address") {
+        Some(v) => v
+            .parse::<Address>()
+            .map_err(|e| format!("error parsing address: {}", e))?,
+        None => return Err("missing address".to_string()),
+    };
+    let offset = match params.get("offset") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|e| format!("error parsing offset: {}", e))?,
+        None => 0,
+    };
+    let limit = match params.get("limit") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|e| format!("error parsing limit: {}", e))?,
+        None => DEFAULT_PAGE_LIMIT,
+    };
+    Ok((address, offset, limit.min(MAX_PAGE_LIMIT)))
+}
+
+// render the block tree as a Graphviz digraph, edges go from parent to child and the blocks of
+// the longest chain are filled
+fn block_tree_dot(nodes: &[BlockTreeNodeJson]) -> String {
+    let mut dot = String::from("digraph blocktree {\n    rankdir=LR;\n    node [shape=box];\n");
+    for node in nodes.iter() {
+        let miner = match &node.miner {
+            Some(miner) => &miner[..8],
+            None => "genesis",
+        };
+        let style = if node.main_chain {
+            ", style=filled, fillcolor=lightblue"
+        } else {
+            ""
+        };
+        dot.push_str(&format!(
+            "    \"{}\" [label=\"{} {}\\n{}\\n{} txs\"{}];\n",
+            node.hash,
+            node.height,
+            &node.hash[..8],
+            miner,
+            node.tx_count,
+            style
+        ));
+    }
+    // the base of a chain started from a snapshot has no parent node
+    let hashes: HashSet<&str> = nodes.iter().map(|node| node.hash.as_str()).collect();
+    for node in nodes
+        .iter()
+        .filter(|node| hashes.contains(node.parent.as_str()))
+    {
+        dot.push_str(&format!("    \"{}\" -> \"{}\";\n", node.parent, node.hash));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -68,6 +143,7 @@ impl Server {
         tx_mempool: &Arc<Mutex<Mempool>>,
         state: &Arc<Mutex<State>>,
         bts_map: &Arc<Mutex<BlockToStateMap>>,
+        sync: &Arc<Mutex<SyncState>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -79,6 +155,7 @@ impl Server {
             tx_mempool: Arc::clone(tx_mempool),
             state: Arc::clone(state),
             bts_map: Arc::clone(bts_map),
+            sync: Arc::clone(sync),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -89,6 +166,7 @@ impl Server {
                 let state = Arc::clone(&server.state);
                 let tx_mempool = Arc::clone(&server.tx_mempool);
                 let bts_map = Arc::clone(&server.bts_map);
+                let sync = Arc::clone(&server.sync);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -101,6 +179,10 @@ impl Server {
                     };
                     match url.path() {
                         "/miner/start" => {
+                            if sync.lock().unwrap().is_light() {
+                                respond_result!(req, false, "not available on a light node");
+                                return;
+                            }
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
                             let lambda = match params.get("lambda") {
@@ -125,6 +207,10 @@ impl Server {
                             respond_result!(req, true, "ok");
                         }
                         "/tx-generator/start" => {
+                            if sync.lock().unwrap().is_light() {
+                                respond_result!(req, false, "not available on a light node");
+                                return;
+                            }
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
                             let theta = match params.get("theta") {
@@ -152,6 +238,105 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/sync" => {
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let sync_with_lock = sync.lock().unwrap();
+                            let progress = sync_with_lock.progress(&blockchain_with_lock);
+                            std::mem::drop(blockchain_with_lock);
+                            std::mem::drop(sync_with_lock);
+                            respond_json!(req, progress);
+                        }
+                        "/light/watched" => {
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let sync_with_lock = sync.lock().unwrap();
+                            let watch_list = match sync_with_lock.light.as_ref() {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "not a light node");
+                                    return;
+                                }
+                            };
+                            let best_height =
+                                blockchain_with_lock.length[&blockchain_with_lock.best_header];
+                            let mut result: Vec<WatchedAddressJson> = Vec::new();
+                            for address in watch_list.watched.iter() {
+                                let mut tx_hashes: Vec<H256> = watch_list
+                                    .address_txs
+                                    .get(address)
+                                    .map(|v| v.iter().cloned().collect())
+                                    .unwrap_or_default();
+                                tx_hashes.sort();
+                                let txs = tx_hashes
+                                    .iter()
+                                    .map(|tx_hash| {
+                                        // a proof of a block that left the best chain no longer counts
+                                        let block_hash =
+                                            watch_list.proven.get(tx_hash).filter(|block_hash| {
+                                                blockchain_with_lock
+                                                    .is_in_best_header_chain(block_hash)
+                                            });
+                                        WatchedTxJson {
+                                            tx_hash: tx_hash.to_string(),
+                                            proven: block_hash.is_some(),
+                                            block: block_hash.map(|v| v.to_string()),
+                                            confirmations: block_hash.map_or(0, |v| {
+                                                best_height - blockchain_with_lock.length[v] + 1
+                                            }),
+                                        }
+                                    })
+                                    .collect();
+                                result.push(WatchedAddressJson {
+                                    address: address.to_string(),
+                                    txs,
+                                });
+                            }
+                            std::mem::drop(blockchain_with_lock);
+                            std::mem::drop(sync_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/blockchain/stats" => {
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let result = StatsJson::from(&*blockchain_with_lock);
+                            std::mem::drop(blockchain_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/blockchain/tree" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let main_chain: HashSet<H256> = blockchain_with_lock
+                                .all_blocks_in_longest_chain()
+                                .into_iter()
+                                .collect();
+                            let nodes: Vec<BlockTreeNodeJson> = blockchain_with_lock
+                                .all_blocks_by_height()
+                                .iter()
+                                .map(|hash| {
+                                    BlockTreeNodeJson::new(
+                                        &blockchain_with_lock.blockchain[hash],
+                                        blockchain_with_lock.length[hash],
+                                        main_chain.contains(hash),
+                                    )
+                                })
+                                .collect();
+                            std::mem::drop(blockchain_with_lock);
+                            match params.get("format").map(|v| v.as_str()) {
+                                None | Some("json") => respond_json!(req, nodes),
+                                Some("dot") => {
+                                    let content_type = "Content-Type: text/vnd.graphviz"
+                                        .parse::<Header>()
+                                        .unwrap();
+                                    let resp = Response::from_string(block_tree_dot(&nodes))
+                                        .with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                }
+                                Some(v) => respond_result!(
+                                    req,
+                                    false,
+                                    format!("unknown format {}, expected json or dot", v)
+                                ),
+                            }
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -164,8 +349,12 @@ impl Server {
                             let mut result = Vec::new();
                             let blockchain_with_lock = blockchain.lock().unwrap();
                             let block_hashes = blockchain_with_lock.all_blocks_in_longest_chain();
+                            // the blocks below the snapshot base have no body
                             for block_hash in block_hashes {
-                                let block = blockchain_with_lock.blockchain[&block_hash].clone();
+                                let block = match blockchain_with_lock.blockchain.get(&block_hash) {
+                                    Some(block) => block.clone(),
+                                    None => continue,
+                                };
                                 let txs = block.content.data;
                                 let mut txs_hashes: Vec<String> = Vec::new();
                                 for tx in txs {
@@ -180,14 +369,363 @@ impl Server {
                             let mut result = 0;
                             let blockchain_with_lock = blockchain.lock().unwrap();
                             let block_hashes = blockchain_with_lock.all_blocks_in_longest_chain();
+                            // the blocks below the snapshot base have no body
                             for block_hash in block_hashes {
-                                let block = blockchain_with_lock.blockchain[&block_hash].clone();
+                                let block = match blockchain_with_lock.blockchain.get(&block_hash) {
+                                    Some(block) => block.clone(),
+                                    None => continue,
+                                };
                                 let txs = block.content.data;
                                 result += txs.len();
                             }
                             std::mem::drop(blockchain_with_lock);
                             respond_json!(req, result);
                         }
+                        "/blockchain/block" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let block_hash = match (params.get("hash"), params.get("height")) {
+                                (Some(v), _) => match v.parse::<H256>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing hash: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                (None, Some(v)) => match v.parse::<u128>() {
+                                    Ok(v) if v <= blockchain_with_lock.longest => {
+                                        blockchain_with_lock
+                                            .ancestor(&blockchain_with_lock.tip(), v)
+                                    }
+                                    Ok(_) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            "block number exceed longest-chain."
+                                        );
+                                        return;
+                                    }
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing block number: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                (None, None) => {
+                                    respond_result!(req, false, "missing block hash or height");
+                                    return;
+                                }
+                            };
+                            let block = match blockchain_with_lock.blockchain.get(&block_hash) {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            let result = BlockJson::new(
+                                block,
+                                blockchain_with_lock.length[&block_hash],
+                                blockchain_with_lock.confirmations(&block_hash),
+                            );
+                            std::mem::drop(blockchain_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/blockchain/tx" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx_hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx hash");
+                                    return;
+                                }
+                            };
+                            let tx_hash = match tx_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let mempool_with_lock = tx_mempool.lock().unwrap();
+                            let result =
+                                if let Some(block_hash) = blockchain_with_lock.find_tx(&tx_hash) {
+                                    let block = &blockchain_with_lock.blockchain[&block_hash];
+                                    let tx = block
+                                        .content
+                                        .data
+                                        .iter()
+                                        .find(|tx| tx.hash() == tx_hash)
+                                        .unwrap();
+                                    TxLookupJson {
+                                        transaction: TransactionJson::from(tx),
+                                        block: Some(block_hash.to_string()),
+                                        height: Some(blockchain_with_lock.length[&block_hash]),
+                                        confirmations: blockchain_with_lock
+                                            .confirmations(&block_hash)
+                                            .unwrap_or(0),
+                                    }
+                                } else if let Some(tx) = mempool_with_lock.tx_map.get(&tx_hash) {
+                                    TxLookupJson {
+                                        transaction: TransactionJson::from(tx),
+                                        block: None,
+                                        height: None,
+                                        confirmations: 0,
+                                    }
+                                } else {
+                                    respond_result!(req, false, "transaction not found");
+                                    return;
+                                };
+                            std::mem::drop(blockchain_with_lock);
+                            std::mem::drop(mempool_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/blockchain/tx-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx_hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx hash");
+                                    return;
+                                }
+                            };
+                            let tx_hash = match tx_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let proof = blockchain_with_lock.tx_proof(&tx_hash);
+                            std::mem::drop(blockchain_with_lock);
+                            match proof {
+                                Some(proof) => respond_json!(req, TxProofJson::from(&proof)),
+                                None => {
+                                    respond_result!(req, false, "transaction not in longest chain")
+                                }
+                            }
+                        }
+                        "/blockchain/utxo-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx_hash = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx hash");
+                                    return;
+                                }
+                            };
+                            let tx_hash = match tx_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let index = match params.get("index").map(|v| v.parse::<u8>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing index: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => 0,
+                            };
+                            // the proof is against the tip header, the live state is the state
+                            // after it
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let state_with_lock = state.lock().unwrap();
+                            let tip = blockchain_with_lock.tip();
+                            let proof = UtxoProof::new(
+                                &blockchain_with_lock.blockchain[&tip].header,
+                                &state_with_lock,
+                                &(tx_hash, index),
+                            );
+                            std::mem::drop(state_with_lock);
+                            std::mem::drop(blockchain_with_lock);
+                            match proof {
+                                Some(proof) => respond_json!(req, UtxoProofJson::from(&proof)),
+                                None => respond_result!(req, false, "output not unspent at tip"),
+                            }
+                        }
+                        "/blockchain/finality" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (hash, is_tx) = match (params.get("block"), params.get("tx")) {
+                                (Some(v), _) => (v, false),
+                                (None, Some(v)) => (v, true),
+                                (None, None) => {
+                                    respond_result!(req, false, "missing block or tx hash");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let block_hash = if is_tx {
+                                match blockchain_with_lock.find_tx_in_longest_chain(&hash) {
+                                    Some(v) => v,
+                                    None => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            "transaction not in longest chain"
+                                        );
+                                        return;
+                                    }
+                                }
+                            } else {
+                                hash
+                            };
+                            let height = match blockchain_with_lock.length.get(&block_hash) {
+                                Some(v) => *v,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            let confirmations = blockchain_with_lock.confirmations(&block_hash);
+                            let result = FinalityJson {
+                                block: block_hash.to_string(),
+                                height,
+                                in_longest_chain: confirmations.is_some(),
+                                confirmations: confirmations.unwrap_or(0),
+                                finalized: blockchain_with_lock.is_final(&block_hash),
+                                finalized_height: blockchain_with_lock.finalized_height(),
+                            };
+                            std::mem::drop(blockchain_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/address/balance" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (address, _, _) = match address_page_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let index = &blockchain_with_lock.address_index;
+                            let result = BalanceJson {
+                                address: address.to_string(),
+                                balance: index.balance(&address),
+                                utxo_count: index.utxos.get(&address).map_or(0, |v| v.len()),
+                            };
+                            std::mem::drop(blockchain_with_lock);
+                            respond_json!(req, result);
+                        }
+                        "/address/utxos" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (address, offset, limit) = match address_page_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            // sorted so that pages are stable
+                            let mut utxos: Vec<((H256, u8), u64)> = blockchain_with_lock
+                                .address_index
+                                .utxos
+                                .get(&address)
+                                .map(|v| v.iter().map(|(k, v)| (*k, *v)).collect())
+                                .unwrap_or_default();
+                            std::mem::drop(blockchain_with_lock);
+                            utxos.sort();
+                            let result = PageJson {
+                                address: address.to_string(),
+                                total: utxos.len(),
+                                offset,
+                                items: utxos
+                                    .iter()
+                                    .skip(offset)
+                                    .take(limit)
+                                    .map(|((tx_hash, index), value)| UtxoJson {
+                                        tx_hash: tx_hash.to_string(),
+                                        index: *index,
+                                        value: *value,
+                                    })
+                                    .collect(),
+                            };
+                            respond_json!(req, result);
+                        }
+                        "/address/history" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (address, offset, limit) = match address_page_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain_with_lock = blockchain.lock().unwrap();
+                            let history = blockchain_with_lock
+                                .address_index
+                                .history
+                                .get(&address)
+                                .map(|v| v.as_slice())
+                                .unwrap_or(&[]);
+                            // most recent first
+                            let result = PageJson {
+                                address: address.to_string(),
+                                total: history.len(),
+                                offset,
+                                items: history
+                                    .iter()
+                                    .rev()
+                                    .skip(offset)
+                                    .take(limit)
+                                    .map(|(tx_hash, block_hash)| HistoryEntryJson {
+                                        tx_hash: tx_hash.to_string(),
+                                        block: block_hash.to_string(),
+                                        height: blockchain_with_lock.length[block_hash],
+                                    })
+                                    .collect(),
+                            };
+                            std::mem::drop(blockchain_with_lock);
+                            respond_json!(req, result);
+                        }
                         "/blockchain/txs-in-mempool" => {
                             let mempool_with_lock = tx_mempool.lock().unwrap();
                             let tx_map = &mempool_with_lock.tx_map;
@@ -230,8 +768,22 @@ impl Server {
                                 respond_result!(req, false, "block number exceed longest-chain.");
                                 return;
                             }
+                            if (height as u128)
+                                < blockchain_with_lock.length[&blockchain_with_lock.base]
+                            {
+                                respond_result!(
+                                    req,
+                                    false,
+                                    "block number below the snapshot base."
+                                );
+                                return;
+                            }
                             let block_hash = longest_chain_blocks[height as usize];
-                            let state: State = bts_map_with_lock.bts_map[&block_hash].clone();
+                            let state: State = bts_map_with_lock.state_at(
+                                &blockchain_with_lock,
+                                &state_with_lock,
+                                &block_hash,
+                            );
                             let utxo = state.utxo;
 
                             for (k, v) in utxo.iter() {
@@ -268,3 +820,55 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn block_tree_dot_with_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&block_a.hash());
+        let block_c = generate_random_block(&genesis_hash);
+        for block in [&block_a, &block_b, &block_c].iter() {
+            blockchain.insert(block);
+        }
+        let main_chain = blockchain.all_blocks_in_longest_chain();
+        let node = |hash: &H256| {
+            BlockTreeNodeJson::new(
+                &blockchain.blockchain[hash],
+                blockchain.length[hash],
+                main_chain.contains(hash),
+            )
+        };
+        let nodes: Vec<BlockTreeNodeJson> =
+            blockchain.all_blocks_by_height().iter().map(node).collect();
+        let dot = block_tree_dot(&nodes);
+
+        let filled = |hash: &H256| {
+            dot.lines()
+                .find(|line| line.starts_with(&format!("    \"{}\" [", hash)))
+                .unwrap()
+                .contains("style=filled")
+        };
+        assert!(filled(&genesis_hash));
+        assert!(filled(&block_a.hash()));
+        assert!(filled(&block_b.hash()));
+        assert!(!filled(&block_c.hash()));
+        // one edge per block but genesis
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\"", genesis_hash, block_c.hash())));
+        assert!(dot.contains(&format!(
+            "label=\"0 {}\\ngenesis\\n1 txs\"",
+            &genesis_hash.to_string()[..8]
+        )));
+
+        // without the genesis node, as for a snapshot base, no edge points to it
+        let dot = block_tree_dot(&nodes[1..]);
+        assert_eq!(dot.matches(" -> ").count(), 1);
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\"", block_a.hash(), block_b.hash())));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use std::collections::HashMap;
+
+//////
+/// AddressIndex follows the longest chain so the funds of an address can be found
+/// without scanning every utxo.
+/// utxos: address -> (tx hash, index) -> value of its unspent outputs at the tip
+/// history: address -> (tx hash, block hash) of the longest chain txs paying or spending it,
+/// oldest first
+/// Blocks are connected when they join the longest chain and disconnected when a reorg
+/// takes them out, from the tip down.
+//////
+#[derive(Debug, Default)]
+pub struct AddressIndex {
+    pub utxos: HashMap<Address, HashMap<(H256, u8), u64>>,
+    pub history: HashMap<Address, Vec<(H256, H256)>>,
+}
+
+impl AddressIndex {
+    pub fn new() -> Self {
+        AddressIndex::default()
+    }
+
+    /// Index a utxo set without its history, used when the chain starts from a snapshot
+    pub fn from_utxos(utxo: &HashMap<(H256, u8), (u64, Address)>) -> Self {
+        let mut index = AddressIndex::new();
+        for (key, (value, owner)) in utxo.iter() {
+            index.utxos.entry(*owner).or_default().insert(*key, *value);
+        }
+        index
+    }
+
+    /// Sum of the unspent outputs of address
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.utxos
+            .get(address)
+            .map(|utxos| utxos.values().sum())
+            .unwrap_or(0)
+    }
+
+    /// Index the txs of a block joining the longest chain.
+    /// spent_output gives the value and owner of an output spent by the block, None for inputs
+    /// that do not refer to a real output (coinbase and allocation).
+    pub fn connect_block<F>(&mut self, block_hash: &H256, block: &Block, spent_output: F)
+    where
+        F: Fn(&(H256, u8)) -> Option<(u64, Address)>,
+    {
+        for tx in block.content.data.iter() {
+            let tx_hash = tx.hash();
+            let mut involved: Vec<Address> = Vec::new();
+            for tx_in in tx.transaction.tx_input.iter() {
+                let key = (tx_in.previous_output, tx_in.index);
+                if let Some((_, owner)) = spent_output(&key) {
+                    if let Some(utxos) = self.utxos.get_mut(&owner) {
+                        utxos.remove(&key);
+                        if utxos.is_empty() {
+                            self.utxos.remove(&owner);
+                        }
+                    }
+                    if !involved.contains(&owner) {
+                        involved.push(owner);
+                    }
+                }
+            }
+            for (idx, tx_out) in tx.transaction.tx_output.iter().enumerate() {
+                self.utxos
+                    .entry(tx_out.recipient_addr)
+                    .or_default()
+                    .insert((tx_hash, idx as u8), tx_out.value);
+                if !involved.contains(&tx_out.recipient_addr) {
+                    involved.push(tx_out.recipient_addr);
+                }
+            }
+            for address in involved {
+                self.history
+                    .entry(address)
+                    .or_default()
+                    .push((tx_hash, *block_hash));
+            }
+        }
+    }
+
+    /// Take back the txs of a block leaving the longest chain, it must be the last block connected
+    pub fn disconnect_block<F>(&mut self, block_hash: &H256, block: &Block, spent_output: F)
+    where
+        F: Fn(&(H256, u8)) -> Option<(u64, Address)>,
+    {
+        for tx in block.content.data.iter().rev() {
+            let tx_hash = tx.hash();
+            let mut involved: Vec<Address> = Vec::new();
+            for (idx, tx_out) in tx.transaction.tx_output.iter().enumerate() {
+                if let Some(utxos) = self.utxos.get_mut(&tx_out.recipient_addr) {
+                    utxos.remove(&(tx_hash, idx as u8));
+                    if utxos.is_empty() {
+                        self.utxos.remove(&tx_out.recipient_addr);
+                    }
+                }
+                if !involved.contains(&tx_out.recipient_addr) {
+                    involved.push(tx_out.recipient_addr);
+                }
+            }
+            for tx_in in tx.transaction.tx_input.iter() {
+                let key = (tx_in.previous_output, tx_in.index);
+                if let Some((value, owner)) = spent_output(&key) {
+                    self.utxos.entry(owner).or_default().insert(key, value);
+                    if !involved.contains(&owner) {
+                        involved.push(owner);
+                    }
+                }
+            }
+            for address in involved {
+                if let Some(history) = self.history.get_mut(&address) {
+                    if history.last() == Some(&(tx_hash, *block_hash)) {
+                        history.pop();
+                    }
+                    if history.is_empty() {
+                        self.history.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::Blockchain;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use crate::types::state::State;
+    use crate::types::transaction::{SignedTransaction, Transaction, TxIn, TxOut};
+
+    #[test]
+    fn follow_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let ico = State::ico_address();
+        let allocation_hash = blockchain.blockchain[&genesis_hash].content.data[0].hash();
+        assert_eq!(blockchain.address_index.balance(&ico), 100000);
+
+        // block a pays 60000 of the allocation to address, the change goes back to ico
+        let address = generate_random_address();
+        let tx = SignedTransaction {
+            transaction: Transaction {
+                tx_input: vec![TxIn {
+                    previous_output: allocation_hash,
+                    index: 0,
+                }],
+                tx_output: vec![
+                    TxOut {
+                        recipient_addr: address,
+                        value: 60000,
+                    },
+                    TxOut {
+                        recipient_addr: ico,
+                        value: 40000,
+                    },
+                ],
+            },
+            public_key: Vec::new(),
+            signature: Vec::new(),
+        };
+        let mut block_a = generate_random_block(&genesis_hash);
+        block_a.content.data.push(tx.clone());
+        blockchain.insert(&block_a);
+        let index = &blockchain.address_index;
+        assert_eq!(index.balance(&ico), 40000);
+        assert_eq!(index.balance(&address), 60000);
+        assert_eq!(index.utxos[&ico].len(), 1);
+        assert_eq!(
+            index.history[&ico],
+            vec![(allocation_hash, genesis_hash), (tx.hash(), block_a.hash())]
+        );
+        assert_eq!(index.history[&address], vec![(tx.hash(), block_a.hash())]);
+
+        // a longer branch without the tx takes block a out
+        let block_b = generate_random_block(&genesis_hash);
+        let block_c = generate_random_block(&block_b.hash());
+        blockchain.insert(&block_b);
+        blockchain.insert(&block_c);
+        assert_eq!(blockchain.tip(), block_c.hash());
+        let index = &blockchain.address_index;
+        assert_eq!(index.balance(&ico), 100000);
+        assert_eq!(index.balance(&address), 0);
+        assert_eq!(index.history[&ico].len(), 1);
+        assert!(!index.history.contains_key(&address));
+    }
+}
@@ -0,0 +1,120 @@
+use super::Blockchain;
+use crate::types::block::Block;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+//////
+/// ChainFileReader reads the blocks of a chain file written by Blockchain::export_chain.
+/// A chain file is a sequence of records, parents before children, each record is a 4-byte
+/// big endian length followed by the bincode serialized block (same framing as the block store).
+//////
+pub struct ChainFileReader {
+    reader: BufReader<File>,
+}
+
+impl ChainFileReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(ChainFileReader {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+}
+
+impl Iterator for ChainFileReader {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut size_buffer = [0u8; 4];
+        match self.reader.read_exact(&mut size_buffer) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let size = u32::from_be_bytes(size_buffer) as usize;
+        // read through take so a corrupted length does not allocate before failing
+        let mut payload = Vec::new();
+        if let Err(e) = (&mut self.reader)
+            .take(size as u64)
+            .read_to_end(&mut payload)
+        {
+            return Some(Err(e));
+        }
+        if payload.len() < size {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chain file ends inside a block",
+            )));
+        }
+        Some(
+            bincode::deserialize(&payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        )
+    }
+}
+
+impl Blockchain {
+    /// Write the blocks of the longest chain, or of the whole block tree, to a chain file,
+    /// genesis first and parents before children
+    /// returns the number of blocks written
+    pub fn export_chain(&self, path: &Path, whole_tree: bool) -> io::Result<usize> {
+        let hashes = if whole_tree {
+            self.all_blocks_by_height()
+        } else {
+            self.all_blocks_in_longest_chain()
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut count = 0;
+        // the blocks below the snapshot base have no body
+        for block in hashes.iter().filter_map(|hash| self.blockchain.get(hash)) {
+            let payload = bincode::serialize(block).unwrap();
+            writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+            writer.write_all(&payload)?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::{generate_random_hash, Hashable, H256};
+
+    #[test]
+    fn export_and_read_back() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&block_a.hash());
+        let block_c = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_a);
+        blockchain.insert(&block_b);
+        blockchain.insert(&block_c);
+        let path = std::env::temp_dir().join(format!("bitcoin-chain-{}", generate_random_hash()));
+
+        assert_eq!(blockchain.export_chain(&path, false).unwrap(), 3);
+        let hashes: Vec<H256> = ChainFileReader::open(&path)
+            .unwrap()
+            .map(|block| block.unwrap().hash())
+            .collect();
+        assert_eq!(hashes, vec![genesis_hash, block_a.hash(), block_b.hash()]);
+
+        assert_eq!(blockchain.export_chain(&path, true).unwrap(), 4);
+        let hashes: Vec<H256> = ChainFileReader::open(&path)
+            .unwrap()
+            .map(|block| block.unwrap().hash())
+            .collect();
+        assert_eq!(hashes, blockchain.all_blocks_by_height());
+
+        // a file cut inside a record reports the error after the complete blocks
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let blocks: Vec<io::Result<Block>> = ChainFileReader::open(&path).unwrap().collect();
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks[3].is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+use super::consensus::ConsensusParams;
+use crate::types::address::Address;
+use crate::types::block::{Block, Content, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::State;
+use crate::types::transaction::{SignedTransaction, Transaction, TxIn, TxOut};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+//////
+/// ChainSpec defines a network: its genesis block, the initial coin allocations and the
+/// consensus parameters. It is loaded from a JSON file, e.g.
+/// {
+///   "name": "testnet-1",
+///   "genesis_timestamp": 0,
+///   "genesis_target": "0005ff0000000000000000000000000000000000000000000000000000000000",
+///   "allocations": [{"address": "fd36919b9d230e364e285e46e49f3989e7a68531", "value": 100000}],
+///   "params": {"target_block_time": 2000},
+///   "checkpoints": [{"height": 1000, "hash": "00000b3c..."}]
+/// }
+/// params can be partial, missing fields take their default value.
+/// checkpoints are blocks known to be on the chain, a branch with another block at a checkpoint
+/// height is rejected. They are not part of the genesis, so they can be added as the chain grows.
+/// The genesis block contains one allocation transaction paying every allocation, its input
+/// commits to the name and params, so two specs differing in anything but checkpoints have
+/// different genesis hashes.
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainSpec {
+    pub name: String,
+    pub genesis_timestamp: u128,
+    #[serde(with = "hex_string")]
+    pub genesis_target: H256,
+    pub allocations: Vec<Allocation>,
+    #[serde(default)]
+    pub params: ConsensusParams,
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Allocation {
+    #[serde(with = "hex_string")]
+    pub address: Address,
+    pub value: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub height: u128,
+    #[serde(with = "hex_string")]
+    pub hash: H256,
+}
+
+// hashes and addresses are written as hex strings in the spec file
+mod hex_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Default for ChainSpec {
+    /// The original hard-coded network: genesis at time 0 and 100000 coins to the ICO address
+    fn default() -> Self {
+        ChainSpec {
+            name: "default".to_string(),
+            genesis_timestamp: 0,
+            genesis_target: [
+                0, 5, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0,
+            ]
+            .into(),
+            allocations: vec![Allocation {
+                address: State::ico_address(),
+                value: 100000,
+            }],
+            params: ConsensusParams::default(),
+            checkpoints: Vec::new(),
+        }
+    }
+}
+
+impl ChainSpec {
+    /// Read a chain spec from a JSON file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let spec: ChainSpec = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    /// Reject specs the chain can not run with: values the difficulty retargeting divides by,
+    /// and block limits no block can fit in
+    fn check(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        // output indexes are u8
+        if self.allocations.len() > u8::MAX as usize {
+            return invalid(format!("at most {} allocations are supported", u8::MAX));
+        }
+        if self.params.target_block_time == 0 {
+            return invalid("params.target_block_time must be greater than 0".to_string());
+        }
+        if self.params.max_adjust_factor == 0 {
+            return invalid("params.max_adjust_factor must be greater than 0".to_string());
+        }
+        if self.params.max_block_txs == 0 {
+            return invalid("params.max_block_txs must be at least 1 for the coinbase".to_string());
+        }
+        // the smallest block: a header and a coinbase, the size does not depend on the values
+        let smallest_block = Block {
+            header: self.genesis_block().header,
+            content: Content {
+                data: vec![SignedTransaction::new_coinbase(1, Address::default(), 0)],
+            },
+        };
+        if self.params.max_block_size < smallest_block.size() {
+            return invalid(format!(
+                "params.max_block_size must be at least {} bytes for a header and a coinbase",
+                smallest_block.size()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Hash of everything in the spec that is not already in the genesis block
+    fn chain_id(&self) -> H256 {
+        let serialized = bincode::serialize(&(&self.name, &self.params)).unwrap();
+        digest::digest(&digest::SHA256, &serialized).into()
+    }
+
+    /// The transaction creating the initial allocations, it is not signed and its only
+    /// input refers to the chain id rather than a real output
+    fn allocation_tx(&self) -> SignedTransaction {
+        let tx_output = self
+            .allocations
+            .iter()
+            .map(|allocation| TxOut {
+                recipient_addr: allocation.address,
+                value: allocation.value,
+            })
+            .collect();
+        SignedTransaction {
+            transaction: Transaction {
+                tx_input: vec![TxIn {
+                    previous_output: self.chain_id(),
+                    index: 0,
+                }],
+                tx_output,
+            },
+            public_key: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn genesis_block(&self) -> Block {
+        let transactions = vec![self.allocation_tx()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        Block {
+            header: Header {
+                parent: H256::default(),
+                nonce: 0,
+                difficulty: self.genesis_target,
+                timestamp: self.genesis_timestamp,
+                merkle_root,
+                state_root: self.allocation_state().state_root(),
+            },
+            content: Content { data: transactions },
+        }
+    }
+
+    fn allocation_state(&self) -> State {
+        let allocation_hash = self.allocation_tx().hash();
+        let mut utxo = HashMap::new();
+        for (idx, allocation) in self.allocations.iter().enumerate() {
+            utxo.insert(
+                (allocation_hash, idx as u8),
+                (allocation.value, allocation.address),
+            );
+        }
+        State {
+            utxo,
+            height: 0,
+            coinbase: HashMap::new(),
+        }
+    }
+
+    /// The state at the genesis block, holding the allocations
+    pub fn genesis_state(&self) -> State {
+        for allocation in self.allocations.iter() {
+            println!(
+                "ICO completed. {:?} coins are granted to {:?}",
+                allocation.value, allocation.address
+            );
+        }
+        self.allocation_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+
+    #[test]
+    fn genesis_from_spec() {
+        let spec = ChainSpec::default();
+        let json = serde_json::to_string(&spec).unwrap();
+        assert!(json.contains(&format!("\"{}\"", State::ico_address())));
+        let parsed: ChainSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.genesis_block().hash(), spec.genesis_block().hash());
+
+        // params may be partial or missing
+        let partial: ChainSpec = serde_json::from_str(
+            r#"{"name": "default", "genesis_timestamp": 0,
+                "genesis_target": "0005ff0000000000000000000000000000000000000000000000000000000000",
+                "allocations": [], "params": {"coinbase_maturity": 3}}"#,
+        )
+        .unwrap();
+        assert_eq!(partial.params.coinbase_maturity, 3);
+        assert_eq!(partial.params.max_block_txs, spec.params.max_block_txs);
+
+        // any change in the spec gives another genesis hash
+        let mut other = spec.clone();
+        other.params.coinbase_maturity += 1;
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        // but checkpoints can be added without changing the genesis
+        let mut other = spec.clone();
+        other.checkpoints.push(Checkpoint {
+            height: 1,
+            hash: [1u8; 32].into(),
+        });
+        let json = serde_json::to_string(&other).unwrap();
+        let parsed: ChainSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.checkpoints[0].hash, other.checkpoints[0].hash);
+        assert_eq!(parsed.genesis_block().hash(), spec.genesis_block().hash());
+        let mut other = spec.clone();
+        other.allocations.push(Allocation {
+            address: generate_random_address(),
+            value: 5,
+        });
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        let state = other.genesis_state();
+        assert_eq!(state.utxo.len(), 2);
+        assert_eq!(other.genesis_block().header.state_root, state.state_root());
+        assert_eq!(
+            state.utxo.values().map(|(value, _)| value).sum::<u64>(),
+            100005
+        );
+    }
+
+    // write a default spec with params_json as params, then load it
+    fn load_with_params(params_json: &str) -> io::Result<ChainSpec> {
+        use crate::types::hash::generate_random_hash;
+        let path = std::env::temp_dir().join(format!("bitcoin-spec-{}", generate_random_hash()));
+        let json = format!(
+            r#"{{"name": "default", "genesis_timestamp": 0,
+                "genesis_target": "0005ff0000000000000000000000000000000000000000000000000000000000",
+                "allocations": [], "params": {}}}"#,
+            params_json
+        );
+        fs::write(&path, json).unwrap();
+        let spec = ChainSpec::load(&path);
+        fs::remove_file(&path).unwrap();
+        spec
+    }
+
+    fn rejected_field(params_json: &str) -> String {
+        let e = load_with_params(params_json).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.to_string()
+    }
+
+    #[test]
+    fn reject_zero_target_block_time() {
+        assert!(load_with_params("{}").is_ok());
+        assert!(rejected_field(r#"{"target_block_time": 0}"#).contains("target_block_time"));
+    }
+
+    #[test]
+    fn reject_zero_max_adjust_factor() {
+        assert!(rejected_field(r#"{"max_adjust_factor": 0}"#).contains("max_adjust_factor"));
+    }
+
+    #[test]
+    fn reject_zero_max_block_txs() {
+        assert!(rejected_field(r#"{"max_block_txs": 0}"#).contains("max_block_txs"));
+        assert!(load_with_params(r#"{"max_block_txs": 1}"#).is_ok());
+    }
+
+    #[test]
+    fn reject_max_block_size_below_coinbase_block() {
+        assert!(rejected_field(r#"{"max_block_size": 100}"#).contains("max_block_size"));
+        assert!(load_with_params(r#"{"max_block_size": 1000}"#).is_ok());
+    }
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+//////
+/// ConsensusParams are the rules every node must agree on
+/// target_block_time: expected interval between blocks, in milliseconds (same unit as header timestamp)
+/// retarget_window: the difficulty is adjusted every retarget_window blocks
+/// max_adjust_factor: one adjustment can make the target at most this many times easier or harder
+/// median_time_span: a block's timestamp must be greater than the median of this many previous blocks
+/// max_future_block_time: a block's timestamp can be at most this many milliseconds ahead of the local clock
+/// initial_subsidy: coins created by the coinbase of a block, before any halving
+/// halving_interval: the subsidy is halved every halving_interval blocks
+/// coinbase_maturity: coinbase outputs can only be spent this many blocks after the coinbase
+/// max_block_size: maximum serialized size of a block in bytes, header included
+/// max_block_txs: maximum number of transactions in a block, coinbase included
+/// finality_depth: a block with this many blocks on top of it in the longest chain is final,
+/// no fork can replace it anymore. 0 disables depth finality, only checkpoints are final then.
+/// missing fields take their default value when deserialized, e.g. from a chain spec
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConsensusParams {
+    pub target_block_time: u128,
+    pub retarget_window: u128,
+    pub max_adjust_factor: u64,
+    pub median_time_span: usize,
+    pub max_future_block_time: u128,
+    pub initial_subsidy: u64,
+    pub halving_interval: u128,
+    pub coinbase_maturity: u128,
+    pub max_block_size: u64,
+    pub max_block_txs: usize,
+    pub finality_depth: u128,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            target_block_time: 2000,
+            retarget_window: 20,
+            max_adjust_factor: 4,
+            median_time_span: 11,
+            max_future_block_time: 2 * 60 * 60 * 1000,
+            initial_subsidy: 1000,
+            halving_interval: 10000,
+            coinbase_maturity: 10,
+            max_block_size: 64 * 1024,
+            max_block_txs: 256,
+            finality_depth: 100,
+        }
+    }
+}
+
+impl ConsensusParams {
+    /// Coins the coinbase of the block at height can create on top of the fees
+    pub fn block_subsidy(&self, height: u128) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_subsidy;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+}
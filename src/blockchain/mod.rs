@@ -1,91 +1,562 @@
-use crate::types::block::Block;
-use crate::types::block::*;
+pub mod address_index;
+pub mod chainfile;
+pub mod chainspec;
+pub mod consensus;
+pub mod snapshot;
+pub mod spv;
+pub mod store;
+pub mod validation;
+pub mod verify;
+
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
 use crate::types::hash::{Hashable, H256};
-use crate::types::merkle::MerkleTree;
-use std::collections::HashMap;
+use address_index::AddressIndex;
+use chainspec::ChainSpec;
+use consensus::ConsensusParams;
+use log::error;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use store::BlockStore;
+
+//////
+/// TipChange is reported by insert when the new block moves the tip of the longest chain
+/// old_tip: the tip before the insert
+/// new_tip: the tip after the insert
+//////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TipChange {
+    pub old_tip: H256,
+    pub new_tip: H256,
+}
+
+//////
+/// ChainStats counts the events of the block tree a node can not find back from the tree itself
+/// reorgs: tip changes that disconnected blocks of the old longest chain
+/// max_reorg_depth: most blocks disconnected by one reorg
+/// orphan_arrivals: blocks received before their parent, counted by the network worker
+//////
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChainStats {
+    pub reorgs: u64,
+    pub max_reorg_depth: usize,
+    pub orphan_arrivals: u64,
+}
+
 #[derive(Debug, Default)]
 //////
 /// Blockchain
 /// tip: the tip of the blockchain
 /// longest: the longest chain length
-/// length: keep track of height of block
+/// length: keep track of height of block (and of header)
+/// work: cumulative chainwork of each block (big endian u256), the tip has the most work
+/// params: consensus parameters, e.g. difficulty retargeting
+/// pow_limit: the easiest target a block can have, the genesis target of the chain spec
+/// store: on-disk block store, every inserted block is also written there if present
+/// store_error: why the store was dropped after a failed write, the node then only keeps the
+/// blocks in memory and is degraded until restarted
+/// headers: the header tree, headers of every block plus headers whose body is not downloaded yet
+/// best_header: the header with the most work, the tip catches up with it as bodies arrive
+/// checkpoints: height -> hash of blocks from the chain spec that every branch must contain
+/// tx_index: tx hash -> hashes of the blocks containing it, one per branch it was mined on
+/// address_index: utxos and tx history of each address along the longest chain
+/// base: the lowest block of the longest chain with a body and a state, genesis unless the chain
+/// was started from a snapshot, blocks below it are only known by their header
+/// base_utxo: the utxo at base when started from a snapshot, the outputs created below base are
+/// looked up there
+/// stats: reorg and orphan counters
+/// invalid: blocks whose body failed validation and the headers built on them, they never
+/// become the best header again
+/// children: header hash -> hashes of the headers built on it
 //////
 pub struct Blockchain {
     pub blockchain: HashMap<H256, Block>,
     pub tip: H256,
     pub longest: u128,
     pub length: HashMap<H256, u128>,
+    pub work: HashMap<H256, H256>,
+    pub params: ConsensusParams,
+    pub pow_limit: H256,
+    pub store: Option<BlockStore>,
+    pub store_error: Option<String>,
+    pub headers: HashMap<H256, Header>,
+    pub best_header: H256,
+    pub checkpoints: HashMap<u128, H256>,
+    pub tx_index: HashMap<H256, Vec<H256>>,
+    pub address_index: AddressIndex,
+    pub base: H256,
+    pub base_utxo: HashMap<(H256, u8), (u64, Address)>,
+    pub stats: ChainStats,
+    pub invalid: HashSet<H256>,
+    pub children: HashMap<H256, Vec<H256>>,
 }
 //////
 /// Blockchain
 ///
 //////
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the default chain spec, only containing the genesis block
     pub fn new() -> Self {
-        let parent: H256 = [0u8; 32].into();
-        let nonce: u32 = 0;
-        // difficulty_setting
-        let difficulty: H256 = [
-            0, 5, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0,
-        ]
-        .into();
-        let timestamp: u128 = 0;
-        let transactions = Vec::new();
-        let merkle_tree = MerkleTree::new(transactions.as_ref());
-        let merkle_root = merkle_tree.root();
-        let header = Header {
-            parent: parent,
-            nonce: nonce,
-            difficulty: difficulty,
-            timestamp: timestamp,
-            merkle_root: merkle_root,
-        };
-        let content = Content { data: transactions };
-        let genesis_block = Block {
-            header: header,
-            content: content,
-        };
+        Blockchain::from_spec(&ChainSpec::default())
+    }
+
+    /// Create a new blockchain only containing the genesis block of spec
+    pub fn from_spec(spec: &ChainSpec) -> Self {
+        let genesis_block = spec.genesis_block();
+        let difficulty = genesis_block.header.difficulty;
         let mut blockchain = HashMap::new();
         let mut length = HashMap::new();
+        let mut work = HashMap::new();
         let genesis_hash = genesis_block.hash();
-        // genesis_block.header.parent = genesis_hash.clone();
 
         let tip = genesis_hash;
         let longest: u128 = 0;
+        let mut tx_index = HashMap::new();
+        for tx in genesis_block.content.data.iter() {
+            tx_index.insert(tx.hash(), vec![genesis_hash]);
+        }
+        // the genesis allocations do not spend real outputs
+        let mut address_index = AddressIndex::new();
+        address_index.connect_block(&genesis_hash, &genesis_block, |_| None);
+        let mut headers = HashMap::new();
+        headers.insert(genesis_hash, genesis_block.header.clone());
         blockchain.insert(genesis_hash, genesis_block);
         length.insert(genesis_hash, 0);
+        work.insert(genesis_hash, difficulty.work());
         Blockchain {
             blockchain: blockchain,
             tip: tip,
             length: length,
             longest: longest,
+            work,
+            params: spec.params.clone(),
+            pow_limit: spec.genesis_target,
+            store: None,
+            store_error: None,
+            headers,
+            best_header: genesis_hash,
+            checkpoints: spec
+                .checkpoints
+                .iter()
+                .map(|checkpoint| (checkpoint.height, checkpoint.hash))
+                .collect(),
+            tx_index,
+            address_index,
+            base: genesis_hash,
+            base_utxo: HashMap::new(),
+            stats: ChainStats::default(),
+            invalid: HashSet::new(),
+            children: HashMap::new(),
         }
     }
 
+    /// Open a blockchain of spec backed by the block store in data_dir.
+    /// Blocks already on disk are inserted again in height order, which recomputes the tip.
+    pub fn open(data_dir: &Path, spec: &ChainSpec) -> io::Result<Self> {
+        let mut blockchain = Blockchain::from_spec(spec);
+        let mut store = BlockStore::open(data_dir)?;
+        let genesis_hash = blockchain.tip;
+        if store.contains(&genesis_hash) {
+            for hash in store.hashes_by_height() {
+                if hash == genesis_hash {
+                    continue;
+                }
+                let block = store.get(&hash)?;
+                blockchain.insert(&block);
+            }
+        } else if !store.offsets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data directory belongs to a chain with another genesis block",
+            ));
+        } else {
+            store.put(&blockchain.blockchain[&genesis_hash], 0)?;
+        }
+        println!(
+            "loaded {} blocks from {}, tip {} at height {}",
+            blockchain.blockchain.len(),
+            data_dir.display(),
+            blockchain.tip,
+            blockchain.longest
+        );
+        blockchain.store = Some(store);
+        // the replay above is not a reorg seen by this node
+        blockchain.stats = ChainStats::default();
+        Ok(blockchain)
+    }
+
     /// Insert a block into blockchain
     /// please use blockchain.insert instead of blockchain.blockchain.insert
     /// the later one will ruin the consistency
-    pub fn insert(&mut self, block: &Block) {
+    /// returns the tip change if the block becomes the new tip, None otherwise
+    pub fn insert(&mut self, block: &Block) -> Option<TipChange> {
         let hash = block.hash();
-        let cur_len = self.length[&block.header.parent] + 1; // get current length
+        // inserting the same block twice must not touch the tip
+        if self.blockchain.contains_key(&hash) || self.invalid.contains(&hash) {
+            return None;
+        }
+        // the header may already be known from headers-first sync
+        if !self.insert_header(&block.header) && !self.headers.contains_key(&hash) {
+            return None;
+        }
+        let cur_len = self.length[&hash];
+        let cur_work = self.work[&hash];
+        // the store is dropped on the first failed write, so it stays a prefix of the chain
+        // that a restart can load, the blocks after it are downloaded again from peers
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.put(block, cur_len) {
+                error!(
+                    "Error writing block {} to block store: {}, blocks are no longer persisted",
+                    hash, e
+                );
+                self.store = None;
+                self.store_error = Some(format!("writing block {}: {}", hash, e));
+            }
+        }
+        self.blockchain.insert(hash, block.clone());
+        for tx in block.content.data.iter() {
+            self.tx_index.entry(tx.hash()).or_default().push(hash);
+        }
 
-        // longest chain change, need to change tip and longest
-        if cur_len > self.longest {
+        // most work chain change, need to change tip and longest
+        // on equal work, the smaller block hash wins so that every node picks the same tip
+        let tip_work = self.work[&self.tip];
+        if cur_work > tip_work || (cur_work == tip_work && hash < self.tip) {
+            let old_tip = self.tip;
             self.tip = hash;
             self.longest = cur_len;
+            let (disconnected, connected) = self.reorg_path(&old_tip, &hash);
+            if !disconnected.is_empty() {
+                self.stats.reorgs += 1;
+                self.stats.max_reorg_depth = self.stats.max_reorg_depth.max(disconnected.len());
+            }
+            self.move_address_index(&disconnected, &connected);
+            return Some(TipChange {
+                old_tip,
+                new_tip: hash,
+            });
+        }
+        None
+    }
+
+    // follow a tip change in the address index, the blocks are given as by reorg_path
+    fn move_address_index(&mut self, disconnected: &[H256], connected: &[H256]) {
+        let blocks = &self.blockchain;
+        let tx_index = &self.tx_index;
+        let base_utxo = &self.base_utxo;
+        let spent_output = |key: &(H256, u8)| {
+            output_of(blocks, tx_index, key).or_else(|| base_utxo.get(key).cloned())
+        };
+        for block_hash in disconnected.iter() {
+            self.address_index
+                .disconnect_block(block_hash, &blocks[block_hash], spent_output);
         }
+        for block_hash in connected.iter() {
+            self.address_index
+                .connect_block(block_hash, &blocks[block_hash], spent_output);
+        }
+    }
+
+    /// Insert a header whose parent header is known, without its body.
+    /// returns false if the header was already known, or built on an invalid block
+    pub fn insert_header(&mut self, header: &Header) -> bool {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return false;
+        }
+        if self.invalid.contains(&header.parent) {
+            self.invalid.insert(hash);
+            return false;
+        }
+        let cur_len = self.length[&header.parent] + 1;
+        let cur_work = self.work[&header.parent].saturating_add(&header.difficulty.work());
         self.length.insert(hash, cur_len);
-        self.blockchain.insert(hash, block.clone());
+        self.work.insert(hash, cur_work);
+        self.headers.insert(hash, header.clone());
+        self.children.entry(header.parent).or_default().push(hash);
+        let best_work = self.work[&self.best_header];
+        if cur_work > best_work || (cur_work == best_work && hash < self.best_header) {
+            self.best_header = hash;
+        }
+        true
+    }
+
+    /// Record that the body of block_hash failed validation: the block and every header built on
+    /// it can not join the longest chain, so the best header goes back to the valid header with
+    /// the most work
+    pub fn mark_invalid(&mut self, block_hash: &H256) {
+        if !self.headers.contains_key(block_hash) {
+            return;
+        }
+        let mut stack = vec![*block_hash];
+        while let Some(hash) = stack.pop() {
+            if self.invalid.insert(hash) {
+                if let Some(children) = self.children.get(&hash) {
+                    stack.extend(children.iter().cloned());
+                }
+            }
+        }
+        if self.invalid.contains(&self.best_header) {
+            // on equal work, the smaller hash wins like in insert_header
+            self.best_header = *self
+                .headers
+                .keys()
+                .filter(|hash| !self.invalid.contains(hash))
+                .max_by(|a, b| self.work[*a].cmp(&self.work[*b]).then(b.cmp(a)))
+                .unwrap();
+        }
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Build a block locator for GetHeaders: hashes of the best header chain from the best
+    /// header back to genesis, dense near the top then with exponentially growing gaps.
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut height = self.length[&self.best_header];
+        let mut cur_hash = self.best_header;
+        let mut step: u128 = 1;
+        loop {
+            cur_hash = self.ancestor(&cur_hash, height);
+            locator.push(cur_hash);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Answer GetHeaders: find the first locator hash on our longest chain (genesis if none),
+    /// then return the headers following it on the longest chain, up to and including stop,
+    /// at most max_headers of them.
+    pub fn headers_after(&self, locator: &[H256], stop: &H256, max_headers: usize) -> Vec<Header> {
+        let fork_height = locator
+            .iter()
+            .find(|hash| {
+                self.headers.contains_key(hash)
+                    && self.length[hash] <= self.longest
+                    && self.ancestor(&self.tip, self.length[hash]) == **hash
+            })
+            .map(|hash| self.length[hash])
+            .unwrap_or(0);
+        let last_height = self.longest.min(fork_height + max_headers as u128);
+        let mut headers = Vec::new();
+        let mut cur_hash = self.ancestor(&self.tip, last_height);
+        while self.length[&cur_hash] > fork_height {
+            headers.push(self.headers[&cur_hash].clone());
+            cur_hash = self.headers[&cur_hash].parent;
+        }
+        headers.reverse();
+        if let Some(stop_idx) = headers.iter().position(|header| header.hash() == *stop) {
+            headers.truncate(stop_idx + 1);
+        }
+        headers
+    }
+
+    /// Hashes of the blocks on the best header chain whose body is missing, from the lowest
+    /// height up, at most max of them
+    pub fn missing_bodies(&self, max: usize) -> Vec<H256> {
+        let mut missing = Vec::new();
+        let mut cur_hash = self.best_header;
+        while !self.blockchain.contains_key(&cur_hash) {
+            missing.push(cur_hash);
+            cur_hash = self.headers[&cur_hash].parent;
+        }
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    /// Get the last block's hash of the longest (most work) chain
     pub fn tip(&self) -> H256 {
         self.tip
     }
 
-    /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
+    /// Walk back from block_hash along its own branch to the ancestor at the given height
+    pub fn ancestor(&self, block_hash: &H256, height: u128) -> H256 {
+        let mut cur_hash = *block_hash;
+        while self.length[&cur_hash] > height {
+            cur_hash = self.headers[&cur_hash].parent;
+        }
+        cur_hash
+    }
+
+    /// Whether block_hash is a block of the longest chain
+    pub fn is_in_longest_chain(&self, block_hash: &H256) -> bool {
+        match self.length.get(block_hash) {
+            Some(height) => {
+                self.blockchain.contains_key(block_hash)
+                    && *height <= self.longest
+                    && self.ancestor(&self.tip, *height) == *block_hash
+            }
+            None => false,
+        }
+    }
+
+    /// Whether block_hash is in the chain of the best header, its body may be missing
+    pub fn is_in_best_header_chain(&self, block_hash: &H256) -> bool {
+        match self.length.get(block_hash) {
+            Some(height) => {
+                *height <= self.length[&self.best_header]
+                    && self.ancestor(&self.best_header, *height) == *block_hash
+            }
+            None => false,
+        }
+    }
+
+    /// Number of blocks of the longest chain from block_hash up to the tip, the block included.
+    /// None if the block is not in the longest chain
+    pub fn confirmations(&self, block_hash: &H256) -> Option<u128> {
+        if !self.is_in_longest_chain(block_hash) {
+            return None;
+        }
+        Some(self.longest - self.length[block_hash] + 1)
+    }
+
+    /// Height up to which the longest chain is final: finality_depth blocks below the tip,
+    /// or the highest checkpoint reached if that is higher
+    pub fn finalized_height(&self) -> u128 {
+        let by_depth = if self.params.finality_depth == 0 {
+            0
+        } else {
+            self.longest.saturating_sub(self.params.finality_depth)
+        };
+        let by_checkpoint = self
+            .checkpoints
+            .keys()
+            .filter(|height| **height <= self.longest)
+            .max()
+            .cloned()
+            .unwrap_or(0);
+        by_depth.max(by_checkpoint)
+    }
+
+    /// Whether block_hash is in the longest chain at or below the finalized height,
+    /// such a block can not be reverted by a fork
+    pub fn is_final(&self, block_hash: &H256) -> bool {
+        self.is_in_longest_chain(block_hash) && self.length[block_hash] <= self.finalized_height()
+    }
+
+    /// Number of blocks of the longest chain with a body, from base up to the tip
+    pub fn main_chain_blocks(&self) -> u128 {
+        self.longest - self.length[&self.base] + 1
+    }
+
+    /// Number of blocks with a body off the longest chain
+    pub fn stale_blocks(&self) -> u128 {
+        self.blockchain.len() as u128 - self.main_chain_blocks()
+    }
+
+    /// Mean time in milliseconds between two blocks of the longest chain, from the block at height 1
+    /// to the tip since the genesis timestamp is not a mining time.
+    /// None until the longest chain has two mined blocks
+    pub fn mean_block_interval(&self) -> Option<f64> {
+        if self.longest < 2 {
+            return None;
+        }
+        let first = &self.headers[&self.ancestor(&self.tip, 1)];
+        let last = &self.headers[&self.tip];
+        let elapsed = last.timestamp.saturating_sub(first.timestamp);
+        Some(elapsed as f64 / (self.longest - 1) as f64)
+    }
+
+    /// Find the block containing tx_hash, the one in the longest chain if there is one,
+    /// otherwise a side block
+    pub fn find_tx(&self, tx_hash: &H256) -> Option<H256> {
+        let block_hashes = self.tx_index.get(tx_hash)?;
+        block_hashes
+            .iter()
+            .find(|block_hash| self.is_in_longest_chain(block_hash))
+            .or_else(|| block_hashes.first())
+            .cloned()
+    }
+
+    /// Find the block of the longest chain containing tx_hash
+    pub fn find_tx_in_longest_chain(&self, tx_hash: &H256) -> Option<H256> {
+        self.find_tx(tx_hash)
+            .filter(|block_hash| self.is_in_longest_chain(block_hash))
+    }
+
+    /// Get the difficulty a child of parent_hash must carry in its header.
+    /// The difficulty stays the same inside an epoch of retarget_window blocks. At the start of
+    /// an epoch, the target is scaled by actual_time / expected_time of the previous epoch,
+    /// clamped by max_adjust_factor, and never easier than pow_limit. Genesis timestamp is not a
+    /// mining time, so it is never used.
+    pub fn next_difficulty(&self, parent_hash: &H256) -> H256 {
+        let parent = &self.headers[parent_hash];
+        let height = self.length[parent_hash] + 1;
+        let window = self.params.retarget_window;
+        if window == 0 || height % window != 0 {
+            return parent.difficulty;
+        }
+        let epoch_start = if height > window { height - window } else { 1 };
+        let last_height = height - 1;
+        if last_height <= epoch_start {
+            return parent.difficulty;
+        }
+        let first_header = &self.headers[&self.ancestor(parent_hash, epoch_start)];
+        // the params come from the chain spec, keep the math in range of mul_div
+        let expected_time = (last_height - epoch_start)
+            .saturating_mul(self.params.target_block_time)
+            .min(u64::MAX as u128);
+        let factor = self.params.max_adjust_factor as u128;
+        if expected_time == 0 || factor == 0 {
+            return parent.difficulty;
+        }
+        let actual_time = parent.timestamp.saturating_sub(first_header.timestamp);
+        // clamp the adjustment
+        let actual_time = actual_time
+            .max(expected_time / factor)
+            .min(expected_time.saturating_mul(factor))
+            .min(u64::MAX as u128);
+        // larger target means easier puzzle, so the target grows when blocks are slow
+        parent
+            .difficulty
+            .mul_div(actual_time as u64, expected_time as u64)
+            .min(self.pow_limit)
+    }
+
+    /// Get the blocks to move the tip from old_tip to new_tip
+    /// returns (disconnected, connected):
+    /// disconnected: blocks on the old branch, ordered from old_tip down to (excluding) the fork point
+    /// connected: blocks on the new branch, ordered from (excluding) the fork point up to new_tip
+    pub fn reorg_path(&self, old_tip: &H256, new_tip: &H256) -> (Vec<H256>, Vec<H256>) {
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        let mut old_cur = *old_tip;
+        let mut new_cur = *new_tip;
+        // walk the higher branch down until both are at the same height
+        while self.length[&old_cur] > self.length[&new_cur] {
+            disconnected.push(old_cur);
+            old_cur = self.headers[&old_cur].parent;
+        }
+        while self.length[&new_cur] > self.length[&old_cur] {
+            connected.push(new_cur);
+            new_cur = self.headers[&new_cur].parent;
+        }
+        // then walk both down until they meet at the fork point
+        while old_cur != new_cur {
+            disconnected.push(old_cur);
+            old_cur = self.headers[&old_cur].parent;
+            connected.push(new_cur);
+            new_cur = self.headers[&new_cur].parent;
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Get all blocks' hashes in the blockchain ordered by height, parents always come before children
+    pub fn all_blocks_by_height(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = self.blockchain.keys().cloned().collect();
+        hashes.sort_by_key(|hash| (self.length[hash], *hash));
+        hashes
+    }
+
+    /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip,
+    /// the blocks below base have no body
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut longest_chain = Vec::new();
         let mut cur_block_hash = self.tip;
@@ -97,7 +568,7 @@ impl Blockchain {
 
         for _ in 0..self.longest {
             longest_chain.push(cur_block_hash);
-            cur_block_hash = self.blockchain[&cur_block_hash].header.parent;
+            cur_block_hash = self.headers[&cur_block_hash].parent;
         }
         longest_chain.push(cur_block_hash);
         longest_chain.reverse();
@@ -106,6 +577,22 @@ impl Blockchain {
     }
 }
 
+// value and recipient of the output key, found through the tx index
+fn output_of(
+    blocks: &HashMap<H256, Block>,
+    tx_index: &HashMap<H256, Vec<H256>>,
+    key: &(H256, u8),
+) -> Option<(u64, Address)> {
+    let block_hash = tx_index.get(&key.0)?.first()?;
+    let tx = blocks[block_hash]
+        .content
+        .data
+        .iter()
+        .find(|tx| tx.hash() == key.0)?;
+    let tx_out = tx.transaction.tx_output.get(key.1 as usize)?;
+    Some((tx_out.value, tx_out.recipient_addr))
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -122,6 +609,406 @@ mod tests {
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn insert_reports_tip_change() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&genesis_hash);
+        let change = blockchain.insert(&block_a).unwrap();
+        assert_eq!(change.old_tip, genesis_hash);
+        assert_eq!(change.new_tip, block_a.hash());
+        // a side fork with the same work only moves the tip if its hash is smaller
+        let change = blockchain.insert(&block_b);
+        assert_eq!(change.is_some(), block_b.hash() < block_a.hash());
+        let (side_hash, main_hash) = if block_b.hash() < block_a.hash() {
+            (block_a.hash(), block_b.hash())
+        } else {
+            (block_b.hash(), block_a.hash())
+        };
+        assert_eq!(blockchain.tip(), main_hash);
+        // re-inserting a known block is a no-op
+        assert!(blockchain.insert(&block_a).is_none());
+        // extending the side fork makes it the most work chain
+        let block_c = generate_random_block(&side_hash);
+        let change = blockchain.insert(&block_c).unwrap();
+        assert_eq!(change.old_tip, main_hash);
+        assert_eq!(change.new_tip, block_c.hash());
+        assert_eq!(blockchain.tip(), block_c.hash());
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        let mut blockchain = Blockchain::new();
+        blockchain.params.target_block_time = 1000;
+        blockchain.params.retarget_window = 4;
+        blockchain.params.max_adjust_factor = 4;
+        let difficulty: H256 = [
+            0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0,
+        ]
+        .into();
+        // blocks at height 1, 2, 3 are two times slower than the target
+        let mut parent_hash = blockchain.tip();
+        for timestamp in [1000, 3000, 5000].iter() {
+            let mut block = generate_random_block(&parent_hash);
+            block.header.timestamp = *timestamp;
+            block.header.difficulty = difficulty;
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        let doubled: H256 = [
+            0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0,
+        ]
+        .into();
+        assert_eq!(blockchain.next_difficulty(&parent_hash), doubled);
+
+        // inside an epoch the difficulty of the parent is kept
+        let grandparent_hash = blockchain.blockchain[&parent_hash].header.parent;
+        assert_eq!(blockchain.next_difficulty(&grandparent_hash), difficulty);
+
+        // a very slow epoch is clamped by max_adjust_factor
+        let mut block = generate_random_block(&grandparent_hash);
+        block.header.timestamp = 1000000;
+        block.header.difficulty = difficulty;
+        blockchain.insert(&block);
+        let clamped: H256 = [
+            0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0,
+        ]
+        .into();
+        assert_eq!(blockchain.next_difficulty(&block.hash()), clamped);
+        // slow epochs can not make the target easier than the genesis one
+        blockchain.pow_limit = doubled;
+        assert_eq!(blockchain.next_difficulty(&block.hash()), doubled);
+        blockchain.pow_limit = ChainSpec::default().genesis_target;
+
+        // params that would divide by zero keep the parent difficulty
+        blockchain.params.max_adjust_factor = 0;
+        assert_eq!(blockchain.next_difficulty(&block.hash()), difficulty);
+        blockchain.params.max_adjust_factor = 4;
+        blockchain.params.target_block_time = 0;
+        assert_eq!(blockchain.next_difficulty(&block.hash()), difficulty);
+    }
+
+    #[test]
+    fn tip_by_most_work() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy: H256 = [10; 32].into();
+        let hard: H256 = [
+            0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0,
+        ]
+        .into();
+        // a long chain of easy blocks
+        let mut parent_hash = genesis_hash;
+        for _ in 0..3 {
+            let mut block = generate_random_block(&parent_hash);
+            block.header.difficulty = easy;
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        // a single hard block has more work than the three easy ones
+        let mut hard_block = generate_random_block(&genesis_hash);
+        hard_block.header.difficulty = hard;
+        let change = blockchain.insert(&hard_block).unwrap();
+        assert_eq!(change.old_tip, parent_hash);
+        assert_eq!(blockchain.tip(), hard_block.hash());
+        assert_eq!(blockchain.longest, 1);
+        assert_eq!(
+            blockchain.all_blocks_in_longest_chain(),
+            vec![genesis_hash, hard_block.hash()]
+        );
+    }
+
+    #[test]
+    fn reopen_from_data_dir() {
+        use crate::types::hash::generate_random_hash;
+        let dir = std::env::temp_dir().join(format!("bitcoin-chain-{}", generate_random_hash()));
+        let mut blockchain = Blockchain::open(&dir, &ChainSpec::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&block_a.hash());
+        let block_c = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_a);
+        blockchain.insert(&block_b);
+        blockchain.insert(&block_c);
+        std::mem::drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir, &ChainSpec::default()).unwrap();
+        assert_eq!(blockchain.blockchain.len(), 4);
+        assert_eq!(blockchain.tip(), block_b.hash());
+        assert_eq!(blockchain.longest, 2);
+        std::mem::drop(blockchain);
+
+        // the store belongs to the default genesis block
+        let other_spec = ChainSpec {
+            name: "other".to_string(),
+            ..Default::default()
+        };
+        assert!(Blockchain::open(&dir, &other_spec).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_does_not_count_reorgs() {
+        use crate::types::hash::generate_random_hash;
+        let dir = std::env::temp_dir().join(format!("bitcoin-chain-{}", generate_random_hash()));
+        let mut blockchain = Blockchain::open(&dir, &ChainSpec::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        // the replay goes by height, block_x is the tip until block_y2 outworks it
+        let mut block_x = generate_random_block(&genesis_hash);
+        block_x.header.difficulty = [1; 32].into();
+        let block_y1 = generate_random_block(&genesis_hash);
+        let mut block_y2 = generate_random_block(&block_y1.hash());
+        let mut hard = [0; 32];
+        hard[2] = 1;
+        block_y2.header.difficulty = hard.into();
+        blockchain.insert(&block_x);
+        blockchain.insert(&block_y1);
+        blockchain.insert(&block_y2);
+        assert_eq!(blockchain.stats.reorgs, 1);
+        std::mem::drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir, &ChainSpec::default()).unwrap();
+        assert_eq!(blockchain.tip(), block_y2.hash());
+        assert_eq!(blockchain.stats, ChainStats::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_failure_degrades() {
+        use crate::types::hash::generate_random_hash;
+        let dir = std::env::temp_dir().join(format!("bitcoin-chain-{}", generate_random_hash()));
+        let mut blockchain = Blockchain::open(&dir, &ChainSpec::default()).unwrap();
+        let block_a = generate_random_block(&blockchain.tip());
+        let block_b = generate_random_block(&block_a.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the block is kept in memory but the store is not written any more
+        blockchain.insert(&block_a);
+        assert_eq!(blockchain.tip(), block_a.hash());
+        assert!(blockchain.store.is_none());
+        assert!(blockchain
+            .store_error
+            .as_ref()
+            .unwrap()
+            .contains(&block_a.hash().to_string()));
+        std::fs::create_dir_all(&dir).unwrap();
+        blockchain.insert(&block_b);
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reorg_path_to_other_branch() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&block_a.hash());
+        let block_c = generate_random_block(&genesis_hash);
+        let block_d = generate_random_block(&block_c.hash());
+        let block_e = generate_random_block(&block_d.hash());
+        for block in [&block_a, &block_b, &block_c, &block_d, &block_e].iter() {
+            blockchain.insert(block);
+        }
+        let (disconnected, connected) = blockchain.reorg_path(&block_b.hash(), &block_e.hash());
+        assert_eq!(disconnected, vec![block_b.hash(), block_a.hash()]);
+        assert_eq!(
+            connected,
+            vec![block_c.hash(), block_d.hash(), block_e.hash()]
+        );
+        // extending the tip disconnects nothing
+        let (disconnected, connected) = blockchain.reorg_path(&block_d.hash(), &block_e.hash());
+        assert!(disconnected.is_empty());
+        assert_eq!(connected, vec![block_e.hash()]);
+    }
+
+    #[test]
+    fn invalid_block_leaves_best_header() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a = generate_random_block(&genesis_hash);
+        let block_b = generate_random_block(&block_a.hash());
+        let block_c = generate_random_block(&block_b.hash());
+        let block_d = generate_random_block(&block_a.hash());
+        blockchain.insert(&block_a);
+        blockchain.insert_header(&block_b.header);
+        blockchain.insert_header(&block_c.header);
+        blockchain.insert_header(&block_d.header);
+        assert_eq!(blockchain.best_header, block_c.hash());
+        assert_eq!(
+            blockchain.missing_bodies(10),
+            vec![block_b.hash(), block_c.hash()]
+        );
+
+        // the body of b fails, c goes with it and sync moves on to the side branch
+        blockchain.mark_invalid(&block_b.hash());
+        assert!(blockchain.invalid.contains(&block_c.hash()));
+        assert!(!blockchain.invalid.contains(&block_d.hash()));
+        assert_eq!(blockchain.best_header, block_d.hash());
+        assert_eq!(blockchain.missing_bodies(10), vec![block_d.hash()]);
+        blockchain.mark_invalid(&block_d.hash());
+        assert_eq!(blockchain.best_header, block_a.hash());
+        assert!(blockchain.missing_bodies(10).is_empty());
+
+        // the block itself, headers and blocks built on it are refused
+        assert_eq!(blockchain.insert(&block_b), None);
+        assert!(!blockchain.blockchain.contains_key(&block_b.hash()));
+        let block_e = generate_random_block(&block_c.hash());
+        assert!(!blockchain.insert_header(&block_e.header));
+        assert!(blockchain.invalid.contains(&block_e.hash()));
+        assert!(!blockchain.headers.contains_key(&block_e.hash()));
+        let block_f = generate_random_block(&block_e.hash());
+        assert_eq!(blockchain.insert(&block_f), None);
+        assert!(!blockchain.blockchain.contains_key(&block_f.hash()));
+    }
+
+    #[test]
+    fn stats_count_reorgs() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.mean_block_interval(), None);
+        let mut block_a = generate_random_block(&genesis_hash);
+        block_a.header.timestamp = 1000;
+        let mut block_b = generate_random_block(&block_a.hash());
+        block_b.header.timestamp = 4000;
+        blockchain.insert(&block_a);
+        blockchain.insert(&block_b);
+        assert_eq!(blockchain.mean_block_interval(), Some(3000.0));
+
+        // a side branch overtaking a and b, d or e takes the tip in one reorg of depth 2
+        let block_c = generate_random_block(&genesis_hash);
+        let block_d = generate_random_block(&block_c.hash());
+        let block_e = generate_random_block(&block_d.hash());
+        for block in [&block_c, &block_d, &block_e].iter() {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), block_e.hash());
+        assert_eq!(
+            blockchain.stats,
+            ChainStats {
+                reorgs: 1,
+                max_reorg_depth: 2,
+                orphan_arrivals: 0,
+            }
+        );
+        assert_eq!(blockchain.main_chain_blocks(), 4);
+        assert_eq!(blockchain.stale_blocks(), 2);
+    }
+
+    #[test]
+    fn headers_first() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut blocks = vec![generate_random_block(&genesis_hash)];
+        for _ in 0..29 {
+            let parent = blocks.last().unwrap().hash();
+            blocks.push(generate_random_block(&parent));
+        }
+        for block in blocks[..20].iter() {
+            blockchain.insert(block);
+        }
+        let locator = blockchain.locator();
+        assert_eq!(locator[0], blocks[19].hash());
+        assert_eq!(*locator.last().unwrap(), genesis_hash);
+        assert!(locator.len() < 20);
+
+        // a peer at block 4 gets the headers after it, up to stop or the limit
+        let peer_locator = vec![blocks[4].hash(), genesis_hash];
+        let headers = blockchain.headers_after(&peer_locator, &H256::default(), 2000);
+        assert_eq!(headers.len(), 15);
+        assert_eq!(headers[0].hash(), blocks[5].hash());
+        let headers = blockchain.headers_after(&peer_locator, &blocks[7].hash(), 2000);
+        assert_eq!(headers.len(), 3);
+        let headers = blockchain.headers_after(&[[9u8; 32].into()], &H256::default(), 4);
+        assert_eq!(headers.last().unwrap().hash(), blocks[3].hash());
+
+        // headers move the best header, not the tip, until the bodies arrive
+        for block in blocks[20..].iter() {
+            assert!(blockchain.insert_header(&block.header));
+        }
+        assert_eq!(blockchain.best_header, blocks[29].hash());
+        assert_eq!(blockchain.tip(), blocks[19].hash());
+        assert_eq!(blockchain.locator()[0], blocks[29].hash());
+        let missing = blockchain.missing_bodies(4);
+        let expected: Vec<H256> = blocks[20..24].iter().map(|block| block.hash()).collect();
+        assert_eq!(missing, expected);
+        for block in blocks[20..].iter() {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), blocks[29].hash());
+        assert!(blockchain.missing_bodies(4).is_empty());
+    }
+
+    #[test]
+    fn finality_and_checkpoints() {
+        let mut blockchain = Blockchain::new();
+        blockchain.params.finality_depth = 2;
+        let genesis_hash = blockchain.tip();
+        let mut blocks = vec![generate_random_block(&genesis_hash)];
+        for _ in 0..3 {
+            let parent = blocks.last().unwrap().hash();
+            blocks.push(generate_random_block(&parent));
+        }
+        for block in blocks.iter() {
+            blockchain.insert(block);
+        }
+        let side = generate_random_block(&blocks[1].hash());
+        blockchain.insert(&side);
+        // the tip is at height 4, so the blocks up to height 2 are final
+        assert_eq!(blockchain.finalized_height(), 2);
+        assert!(blockchain.is_final(&blocks[1].hash()));
+        assert!(!blockchain.is_final(&blocks[2].hash()));
+        assert!(!blockchain.is_final(&side.hash()));
+        assert_eq!(blockchain.confirmations(&blocks[1].hash()), Some(3));
+        assert_eq!(blockchain.confirmations(&side.hash()), None);
+        let allocation_hash = blockchain.blockchain[&genesis_hash].content.data[0].hash();
+        assert_eq!(
+            blockchain.find_tx_in_longest_chain(&allocation_hash),
+            Some(genesis_hash)
+        );
+        assert_eq!(blockchain.find_tx_in_longest_chain(&[3u8; 32].into()), None);
+
+        // a checkpoint is final whatever its depth
+        blockchain.checkpoints.insert(3, blocks[2].hash());
+        assert_eq!(blockchain.finalized_height(), 3);
+        assert!(blockchain.is_final(&blocks[2].hash()));
+        blockchain.params.finality_depth = 0;
+        blockchain.checkpoints.clear();
+        assert_eq!(blockchain.finalized_height(), 0);
+    }
+
+    #[test]
+    fn tx_index_prefers_longest_chain() {
+        use crate::types::address::generate_random_address;
+        use crate::types::transaction::SignedTransaction;
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx = SignedTransaction::new_coinbase(1, generate_random_address(), 5);
+        let mut block_a = generate_random_block(&genesis_hash);
+        block_a.content.data.push(tx.clone());
+        let mut block_b = generate_random_block(&genesis_hash);
+        block_b.content.data.push(tx.clone());
+        blockchain.insert(&block_a);
+        blockchain.insert(&block_b);
+        assert_eq!(blockchain.tx_index[&tx.hash()].len(), 2);
+        let main_hash = blockchain.tip();
+        let side_hash = if main_hash == block_a.hash() {
+            block_b.hash()
+        } else {
+            block_a.hash()
+        };
+        assert_eq!(blockchain.find_tx(&tx.hash()), Some(main_hash));
+        // the side branch takes over
+        let block_c = generate_random_block(&side_hash);
+        blockchain.insert(&block_c);
+        assert_eq!(blockchain.find_tx(&tx.hash()), Some(side_hash));
+        assert_eq!(blockchain.find_tx(&[3u8; 32].into()), None);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,272 @@
+use super::address_index::AddressIndex;
+use super::chainspec::ChainSpec;
+use super::Blockchain;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::{State, UtxoEntry};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// bump when the layout of Snapshot changes, older files are refused
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+//////
+/// Snapshot is the utxo set at a block, so a node can start from it instead of replaying
+/// every block from genesis.
+/// headers: the header chain from height 1 up to the block, checked like headers from peers
+/// block: the block the state is at, it becomes the base of the new chain
+/// utxo: the utxo set after the block, sorted by key, with the height of coinbase outputs
+/// The file is the version (4-byte big endian), the snapshot hash, then the bincode snapshot.
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub headers: Vec<Header>,
+    pub block: Block,
+    pub utxo: Vec<UtxoEntry>,
+}
+
+impl Hashable for Snapshot {
+    fn hash(&self) -> H256 {
+        let serialized = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &serialized).into()
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Snapshot {
+    /// Take the snapshot of block_hash, state must be the state at that block
+    pub fn new(blockchain: &Blockchain, block_hash: &H256, state: &State) -> Self {
+        let mut headers = Vec::new();
+        let mut cur_hash = *block_hash;
+        while blockchain.length[&cur_hash] > 0 {
+            let header = &blockchain.headers[&cur_hash];
+            headers.push(header.clone());
+            cur_hash = header.parent;
+        }
+        headers.reverse();
+        Snapshot {
+            headers,
+            block: blockchain.blockchain[block_hash].clone(),
+            utxo: state.sorted_entries(),
+        }
+    }
+
+    pub fn height(&self) -> u128 {
+        self.headers.len() as u128
+    }
+
+    /// The state at the block of the snapshot
+    pub fn state(&self) -> State {
+        State {
+            utxo: self
+                .utxo
+                .iter()
+                .map(|entry| (entry.key, entry.value))
+                .collect(),
+            height: self.height(),
+            coinbase: self
+                .utxo
+                .iter()
+                .filter_map(|entry| entry.coinbase_height.map(|height| (entry.key.0, height)))
+                .collect(),
+        }
+    }
+
+    /// Write the snapshot to path
+    /// returns the snapshot hash, to be given to the nodes starting from it
+    pub fn write(&self, path: &Path) -> io::Result<H256> {
+        let hash = self.hash();
+        let mut file = File::create(path)?;
+        file.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        file.write_all(hash.as_ref())?;
+        file.write_all(&bincode::serialize(self).unwrap())?;
+        file.sync_all()?;
+        Ok(hash)
+    }
+
+    /// Read a snapshot written by write, refusing other versions and corrupted files
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < 36 {
+            return Err(invalid_data("snapshot file too short"));
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&bytes[..4]);
+        let version = u32::from_be_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "snapshot version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        let mut stored_hash = [0u8; 32];
+        stored_hash.copy_from_slice(&bytes[4..36]);
+        let snapshot: Snapshot = bincode::deserialize(&bytes[36..]).map_err(invalid_data)?;
+        if snapshot.hash() != stored_hash.into() {
+            return Err(invalid_data(
+                "snapshot hash mismatch, the file is corrupted",
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Blockchain {
+    /// Start a blockchain of spec from a snapshot rather than from genesis.
+    /// The headers are checked like headers received from peers. The snapshot must have
+    /// trusted_hash if given, and its utxo set must match the state root of its block.
+    /// The block of the snapshot becomes the base of the chain and a checkpoint, so no fork
+    /// below it is accepted.
+    /// returns the blockchain and the state at its tip
+    pub fn from_snapshot(
+        spec: &ChainSpec,
+        snapshot: &Snapshot,
+        trusted_hash: Option<&H256>,
+        now: u128,
+    ) -> io::Result<(Self, State)> {
+        if let Some(trusted_hash) = trusted_hash {
+            if snapshot.hash() != *trusted_hash {
+                return Err(invalid_data(format!(
+                    "snapshot hash {} is not the trusted hash {}",
+                    snapshot.hash(),
+                    trusted_hash
+                )));
+            }
+        }
+        let mut blockchain = Blockchain::from_spec(spec);
+        for header in snapshot.headers.iter() {
+            blockchain
+                .validate_header(header, now)
+                .map_err(|e| invalid_data(format!("snapshot header {}: {}", header.hash(), e)))?;
+            blockchain.insert_header(header);
+        }
+        let block = &snapshot.block;
+        let block_hash = block.hash();
+        if block_hash != blockchain.best_header {
+            return Err(invalid_data("snapshot block is not the last header"));
+        }
+        if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+            return Err(invalid_data(
+                "snapshot block does not match its merkle root",
+            ));
+        }
+        let state = snapshot.state();
+        if state.state_root() != block.header.state_root {
+            return Err(invalid_data(
+                "snapshot utxo set does not match the state root of its block",
+            ));
+        }
+
+        if block_hash != blockchain.tip {
+            let height = snapshot.height();
+            blockchain.blockchain.insert(block_hash, block.clone());
+            for tx in block.content.data.iter() {
+                blockchain
+                    .tx_index
+                    .entry(tx.hash())
+                    .or_default()
+                    .push(block_hash);
+            }
+            blockchain.tip = block_hash;
+            blockchain.longest = height;
+            blockchain.checkpoints.insert(height, block_hash);
+            blockchain.address_index = AddressIndex::from_utxos(&state.utxo);
+            blockchain.base = block_hash;
+            blockchain.base_utxo = state.utxo.clone();
+        }
+        println!(
+            "started from the snapshot of block {} at height {} with {} utxo",
+            block_hash,
+            snapshot.height(),
+            snapshot.utxo.len()
+        );
+        Ok((blockchain, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut spec = ChainSpec::default();
+        spec.params.retarget_window = 0;
+        let mut source = Blockchain::from_spec(&spec);
+        let mut state = spec.genesis_state();
+        state.height = 3;
+        let address = generate_random_address();
+        state
+            .utxo
+            .insert((generate_random_hash(), 0), (50, address));
+        let coinbase_hash = generate_random_hash();
+        state.utxo.insert((coinbase_hash, 0), (10, address));
+        state.coinbase.insert(coinbase_hash, 2);
+        let now: u128 = 10_000_000_000;
+        let mut parent = source.tip();
+        let mut blocks = Vec::new();
+        for i in 1..=3 {
+            let mut block = generate_random_block(&parent);
+            block.header.difficulty = spec.genesis_target;
+            block.header.timestamp = now - 100 + i;
+            if i == 3 {
+                block.header.state_root = state.state_root();
+            }
+            while block.hash() > block.header.difficulty {
+                block.header.nonce = block.header.nonce.wrapping_add(1);
+            }
+            source.insert(&block);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        let snapshot = Snapshot::new(&source, &parent, &state);
+        let path =
+            std::env::temp_dir().join(format!("bitcoin-snapshot-{}", generate_random_hash()));
+        let hash = snapshot.write(&path).unwrap();
+        let snapshot = Snapshot::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (blockchain, started_state) =
+            Blockchain::from_snapshot(&spec, &snapshot, Some(&hash), now).unwrap();
+        assert_eq!(blockchain.tip(), parent);
+        assert_eq!(blockchain.longest, 3);
+        assert_eq!(blockchain.base, parent);
+        assert_eq!(started_state.state_root(), state.state_root());
+        assert_eq!(started_state.height, 3);
+        assert_eq!(blockchain.address_index.balance(&address), 60);
+        assert_eq!(started_state.coinbase, state.coinbase);
+        // the blocks below the base are only headers and can not be forked from
+        assert!(!blockchain.blockchain.contains_key(&blocks[1].hash()));
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 4);
+        let fork = generate_random_block(&blocks[1].hash());
+        assert!(blockchain.validate_header(&fork.header, now).is_err());
+
+        assert!(Blockchain::from_snapshot(&spec, &snapshot, Some(&[1u8; 32].into()), now).is_err());
+        let mut tampered = snapshot.clone();
+        tampered.utxo[0].value.0 += 1;
+        assert!(Blockchain::from_snapshot(&spec, &tampered, None, now).is_err());
+        // the coinbase height is committed, an output can not be made mature early
+        let mut tampered = snapshot.clone();
+        let coinbase_entry = tampered
+            .utxo
+            .iter_mut()
+            .find(|entry| entry.key.0 == coinbase_hash)
+            .unwrap();
+        coinbase_entry.coinbase_height = Some(0);
+        assert!(Blockchain::from_snapshot(&spec, &tampered, None, now).is_err());
+        let mut unlinked = snapshot;
+        unlinked.headers.remove(1);
+        assert!(Blockchain::from_snapshot(&spec, &unlinked, None, now).is_err());
+    }
+}
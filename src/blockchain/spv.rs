@@ -0,0 +1,213 @@
+use super::Blockchain;
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::{self, MerkleTree};
+use crate::types::state::{State, UtxoEntry};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+//////
+/// TxProof shows that a transaction is in a block without the block body:
+/// the header commits to the transactions through merkle_root, and branch links tx_hash to it.
+/// index: position of the tx in the block
+/// leaf_size: number of txs in the block
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    pub header: Header,
+    pub tx_hash: H256,
+    pub index: usize,
+    pub leaf_size: usize,
+    pub branch: Vec<H256>,
+}
+
+//////
+/// ProofError is the reason a TxProof is rejected
+//////
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    /// the header is not in the header tree
+    UnknownBlock,
+    /// the header is on a side branch of the header tree
+    NotInBestChain,
+    /// the branch does not lead from tx_hash to the merkle root of the header
+    BadBranch,
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::UnknownBlock => write!(f, "unknown block"),
+            ProofError::NotInBestChain => write!(f, "block not in the best header chain"),
+            ProofError::BadBranch => write!(f, "merkle branch mismatch"),
+        }
+    }
+}
+
+impl TxProof {
+    /// Check the branch against the merkle root of the header, the header itself is not checked
+    pub fn verify_branch(&self) -> bool {
+        merkle::verify(
+            &self.header.merkle_root,
+            &self.tx_hash,
+            &self.branch,
+            self.index,
+            self.leaf_size,
+        )
+    }
+}
+
+//////
+/// UtxoProof shows that an output is unspent after a block:
+/// the header commits to the utxo set through state_root, and branch links the entry to it.
+/// index: position of the entry in the utxo set sorted by key
+/// leaf_size: number of utxos after the block
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoProof {
+    pub header: Header,
+    pub entry: UtxoEntry,
+    pub index: usize,
+    pub leaf_size: usize,
+    pub branch: Vec<H256>,
+}
+
+impl UtxoProof {
+    /// Build the proof of the utxo key in state, which must be the state after header
+    pub fn new(header: &Header, state: &State, key: &(H256, u8)) -> Option<Self> {
+        let (entry, index, leaf_size, branch) = state.utxo_proof(key)?;
+        Some(UtxoProof {
+            header: header.clone(),
+            entry,
+            index,
+            leaf_size,
+            branch,
+        })
+    }
+
+    /// Check the branch against the state root of the header, the header itself is not checked
+    pub fn verify_branch(&self) -> bool {
+        merkle::verify(
+            &self.header.state_root,
+            &self.entry.hash(),
+            &self.branch,
+            self.index,
+            self.leaf_size,
+        )
+    }
+}
+
+impl Blockchain {
+    /// Build the proof of a tx in the longest chain, None if the tx is not in it
+    pub fn tx_proof(&self, tx_hash: &H256) -> Option<TxProof> {
+        let block_hash = self.find_tx_in_longest_chain(tx_hash)?;
+        let block = &self.blockchain[&block_hash];
+        let txs = &block.content.data;
+        let index = txs.iter().position(|tx| tx.hash() == *tx_hash)?;
+        Some(TxProof {
+            header: block.header.clone(),
+            tx_hash: *tx_hash,
+            index,
+            leaf_size: txs.len(),
+            branch: MerkleTree::new(txs).proof(index),
+        })
+    }
+
+    /// Check a proof against the header chain, so it only needs headers: the header must be on
+    /// the best header chain and the branch must lead to its merkle root.
+    /// returns the confirmations of the block, counted up to the best header
+    pub fn verify_tx_proof(&self, proof: &TxProof) -> Result<u128, ProofError> {
+        self.confirm_proof(&proof.header, proof.verify_branch())
+    }
+
+    /// Check a utxo proof against the header chain, like verify_tx_proof
+    pub fn verify_utxo_proof(&self, proof: &UtxoProof) -> Result<u128, ProofError> {
+        self.confirm_proof(&proof.header, proof.verify_branch())
+    }
+
+    /// Check the header of a proof is known and on the best header chain, and that its branch
+    /// verified, returns the confirmations of the block
+    fn confirm_proof(&self, header: &Header, branch_ok: bool) -> Result<u128, ProofError> {
+        let block_hash = header.hash();
+        if !self.headers.contains_key(&block_hash) {
+            return Err(ProofError::UnknownBlock);
+        }
+        if !self.is_in_best_header_chain(&block_hash) {
+            return Err(ProofError::NotInBestChain);
+        }
+        if !branch_ok {
+            return Err(ProofError::BadBranch);
+        }
+        Ok(self.length[&self.best_header] - self.length[&block_hash] + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::SignedTransaction;
+
+    #[test]
+    fn prove_and_verify() {
+        let mut full = Blockchain::new();
+        let mut light = Blockchain::new();
+        let genesis_hash = full.tip();
+        let mut block = generate_random_block(&genesis_hash);
+        block.content.data = (1..=5)
+            .map(|height| SignedTransaction::new_coinbase(height, generate_random_address(), 1))
+            .collect();
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        let child = generate_random_block(&block.hash());
+        full.insert(&block);
+        full.insert(&child);
+        // the light chain only has the headers
+        light.insert_header(&block.header);
+        light.insert_header(&child.header);
+
+        for tx in block.content.data.iter() {
+            let proof = full.tx_proof(&tx.hash()).unwrap();
+            assert_eq!(light.verify_tx_proof(&proof), Ok(2));
+        }
+        assert!(full.tx_proof(&[3u8; 32].into()).is_none());
+
+        let mut proof = full.tx_proof(&block.content.data[3].hash()).unwrap();
+        proof.index = 2;
+        assert_eq!(light.verify_tx_proof(&proof), Err(ProofError::BadBranch));
+
+        // a header the light chain does not know, then one on a side branch
+        let other = generate_random_block(&genesis_hash);
+        proof.header = other.header.clone();
+        assert_eq!(light.verify_tx_proof(&proof), Err(ProofError::UnknownBlock));
+        light.insert_header(&other.header);
+        assert_eq!(
+            light.verify_tx_proof(&proof),
+            Err(ProofError::NotInBestChain)
+        );
+    }
+
+    #[test]
+    fn prove_and_verify_utxo() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut state = State::default();
+        let keys: Vec<(H256, u8)> = (0..3).map(|i| ([i; 32].into(), i)).collect();
+        for key in keys.iter() {
+            state.utxo.insert(*key, (7, generate_random_address()));
+        }
+        let mut block = generate_random_block(&genesis_hash);
+        block.header.state_root = state.state_root();
+        blockchain.insert(&block);
+
+        let mut proof = UtxoProof::new(&block.header, &state, &keys[1]).unwrap();
+        assert_eq!(blockchain.verify_utxo_proof(&proof), Ok(1));
+        assert!(UtxoProof::new(&block.header, &state, &([9u8; 32].into(), 0)).is_none());
+        // a changed value no longer matches the commitment
+        proof.entry.value.0 = 8;
+        assert_eq!(
+            blockchain.verify_utxo_proof(&proof),
+            Err(ProofError::BadBranch)
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// start a new block file once the current one grows beyond this size
+const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+const INDEX_FILE_NAME: &str = "index.dat";
+// bincode size of IndexEntry: hash (32) + file_num (4) + offset (8) + height (16)
+const INDEX_ENTRY_SIZE: u64 = 60;
+
+//////
+/// BlockStore keeps every block on disk under a data directory.
+/// blocks are appended to blkNNNNN.dat files, each record is a 4-byte big endian length
+/// followed by the bincode serialized block (same framing as the p2p messages).
+/// index.dat is an append-only list of IndexEntry, read back at startup.
+/// offsets: block hash -> (file number, offset of the record)
+/// heights: height -> hashes of the blocks at this height
+//////
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    file_num: u32,
+    file_len: u64,
+    pub offsets: HashMap<H256, (u32, u64)>,
+    pub heights: BTreeMap<u128, Vec<H256>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct IndexEntry {
+    hash: H256,
+    file_num: u32,
+    offset: u64,
+    height: u128,
+}
+
+impl BlockStore {
+    /// Open the store in dir, creating the directory if needed, and load the index
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut store = BlockStore {
+            dir: dir.to_path_buf(),
+            file_num: 0,
+            file_len: 0,
+            offsets: HashMap::new(),
+            heights: BTreeMap::new(),
+        };
+
+        let index_path = dir.join(INDEX_FILE_NAME);
+        if index_path.exists() {
+            let mut bytes = Vec::new();
+            File::open(&index_path)?.read_to_end(&mut bytes)?;
+            let mut reader = bytes.as_slice();
+            while !reader.is_empty() {
+                let entry: IndexEntry = match bincode::deserialize_from(&mut reader) {
+                    Ok(entry) => entry,
+                    Err(_) => {
+                        // a record cut short by a crash is dropped, the block is fetched again from peers
+                        let valid_len = (bytes.len() - reader.len()) as u64;
+                        let valid_len = valid_len - valid_len % INDEX_ENTRY_SIZE;
+                        OpenOptions::new()
+                            .write(true)
+                            .open(&index_path)?
+                            .set_len(valid_len)?;
+                        break;
+                    }
+                };
+                store.file_num = store.file_num.max(entry.file_num);
+                store
+                    .offsets
+                    .insert(entry.hash, (entry.file_num, entry.offset));
+                store
+                    .heights
+                    .entry(entry.height)
+                    .or_default()
+                    .push(entry.hash);
+            }
+        }
+        let block_path = store.block_file_path(store.file_num);
+        if block_path.exists() {
+            store.file_len = fs::metadata(&block_path)?.len();
+        }
+        Ok(store)
+    }
+
+    fn block_file_path(&self, file_num: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file_num))
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.offsets.contains_key(hash)
+    }
+
+    /// Append a block to the current block file, then record it in the index
+    pub fn put(&mut self, block: &Block, height: u128) -> io::Result<()> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Ok(());
+        }
+        let payload = bincode::serialize(block).unwrap();
+        if self.file_len > 0 && self.file_len + payload.len() as u64 + 4 > MAX_BLOCK_FILE_SIZE {
+            self.file_num += 1;
+            self.file_len = 0;
+        }
+        let offset = self.file_len;
+        let mut block_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.block_file_path(self.file_num))?;
+        block_file.write_all(&(payload.len() as u32).to_be_bytes())?;
+        block_file.write_all(&payload)?;
+        block_file.sync_data()?;
+        self.file_len += payload.len() as u64 + 4;
+
+        // only index the block after its bytes are on disk
+        let entry = IndexEntry {
+            hash,
+            file_num: self.file_num,
+            offset,
+            height,
+        };
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE_NAME))?;
+        index_file.write_all(&bincode::serialize(&entry).unwrap())?;
+        index_file.sync_data()?;
+
+        self.offsets.insert(hash, (self.file_num, offset));
+        self.heights.entry(height).or_default().push(hash);
+        Ok(())
+    }
+
+    /// Read a block back from its block file
+    pub fn get(&self, hash: &H256) -> io::Result<Block> {
+        let (file_num, offset) = match self.offsets.get(hash) {
+            Some(location) => *location,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("block {} not in store", hash),
+                ))
+            }
+        };
+        let mut block_file = File::open(self.block_file_path(file_num))?;
+        block_file.seek(SeekFrom::Start(offset))?;
+        let mut size_buffer = [0u8; 4];
+        block_file.read_exact(&mut size_buffer)?;
+        let mut payload = vec![0u8; u32::from_be_bytes(size_buffer) as usize];
+        block_file.read_exact(&mut payload)?;
+        bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// All stored block hashes, ordered by height so that parents come before children
+    pub fn hashes_by_height(&self) -> Vec<H256> {
+        self.heights.values().flatten().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn put_get_reopen() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-store-{}", generate_random_hash()));
+        let block_a = generate_random_block(&generate_random_hash());
+        let block_b = generate_random_block(&block_a.hash());
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.put(&block_a, 1).unwrap();
+            store.put(&block_b, 2).unwrap();
+            assert_eq!(store.get(&block_b.hash()).unwrap().hash(), block_b.hash());
+        }
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(
+            store.hashes_by_height(),
+            vec![block_a.hash(), block_b.hash()]
+        );
+        assert_eq!(store.get(&block_a.hash()).unwrap().hash(), block_a.hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use super::Blockchain;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use std::collections::HashSet;
+use std::fmt;
+
+//////
+/// BlockError is the reason a block is rejected
+//////
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// the parent field is the null hash
+    NullParent,
+    /// the parent is not in the blockchain
+    UnknownParent,
+    /// the difficulty does not follow the retargeting rule
+    BadDifficulty,
+    /// the block hash is above the difficulty
+    BadProofOfWork,
+    /// merkle_root does not commit to the transactions in the block
+    BadMerkleRoot,
+    /// timestamp is not greater than the median timestamp of the previous blocks
+    TimestampTooOld,
+    /// timestamp is too far ahead of the local clock
+    TimestampTooNew,
+    /// a transaction fails validation against the parent's state
+    InvalidTransaction(H256),
+    /// the serialized block exceeds max_block_size
+    BlockTooLarge,
+    /// the block has more than max_block_txs transactions
+    TooManyTransactions,
+    /// the first transaction is not a coinbase for the block's height, or it pays too much
+    BadCoinbase,
+    /// the block is at a checkpoint height but is not the checkpoint block
+    CheckpointMismatch,
+    /// the block forks off the longest chain below the finalized height
+    ForkBelowFinality,
+    /// state_root does not commit to the utxo set after the block
+    BadStateRoot,
+    /// the block, or a block it is built on, already failed validation
+    KnownInvalid,
+    /// only the header of the block is known, its body is missing
+    MissingBody,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::NullParent => write!(f, "null parent"),
+            BlockError::UnknownParent => write!(f, "unknown parent"),
+            BlockError::BadDifficulty => write!(f, "unexpected difficulty"),
+            BlockError::BadProofOfWork => write!(f, "proof of work"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root mismatch"),
+            BlockError::TimestampTooOld => write!(f, "timestamp not after median time past"),
+            BlockError::TimestampTooNew => write!(f, "timestamp too far in the future"),
+            BlockError::InvalidTransaction(tx_hash) => write!(f, "invalid transaction {}", tx_hash),
+            BlockError::BlockTooLarge => write!(f, "block exceeds the size limit"),
+            BlockError::TooManyTransactions => write!(f, "block exceeds the transaction limit"),
+            BlockError::BadCoinbase => write!(f, "missing or invalid coinbase"),
+            BlockError::CheckpointMismatch => write!(f, "conflicts with a checkpoint"),
+            BlockError::ForkBelowFinality => write!(f, "forks below the finalized height"),
+            BlockError::BadStateRoot => write!(f, "state root mismatch"),
+            BlockError::KnownInvalid => write!(f, "block or ancestor already failed validation"),
+            BlockError::MissingBody => write!(f, "block body missing"),
+        }
+    }
+}
+
+impl Blockchain {
+    /// Median timestamp of the last median_time_span blocks ending at block_hash (included)
+    pub fn median_time_past(&self, block_hash: &H256) -> u128 {
+        let mut timestamps = Vec::new();
+        let mut cur_hash = *block_hash;
+        for _ in 0..self.params.median_time_span.max(1) {
+            let header = &self.headers[&cur_hash];
+            timestamps.push(header.timestamp);
+            if self.length[&cur_hash] == 0 {
+                break;
+            }
+            cur_hash = header.parent;
+        }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Check a header against its parent in the header tree: parent link, checkpoints, finality,
+    /// difficulty, proof of work and timestamp. now is the local clock in milliseconds.
+    pub fn validate_header(&self, header: &Header, now: u128) -> Result<(), BlockError> {
+        if header.parent == H256::default() {
+            return Err(BlockError::NullParent);
+        }
+        if !self.headers.contains_key(&header.parent) {
+            return Err(BlockError::UnknownParent);
+        }
+        if self.invalid.contains(&header.parent) || self.invalid.contains(&header.hash()) {
+            return Err(BlockError::KnownInvalid);
+        }
+        let height = self.length[&header.parent] + 1;
+        if let Some(checkpoint) = self.checkpoints.get(&height) {
+            if header.hash() != *checkpoint {
+                return Err(BlockError::CheckpointMismatch);
+            }
+        }
+        // the longest chain already has a final block at this height, or the branch of the
+        // parent does not contain the final block at the finalized height
+        let finalized_height = self.finalized_height();
+        if height <= finalized_height
+            || self.ancestor(&header.parent, finalized_height)
+                != self.ancestor(&self.tip, finalized_height)
+        {
+            return Err(BlockError::ForkBelowFinality);
+        }
+        if header.difficulty != self.next_difficulty(&header.parent) {
+            return Err(BlockError::BadDifficulty);
+        }
+        if header.hash() > header.difficulty {
+            return Err(BlockError::BadProofOfWork);
+        }
+        if header.timestamp <= self.median_time_past(&header.parent) {
+            return Err(BlockError::TimestampTooOld);
+        }
+        if header.timestamp > now + self.params.max_future_block_time {
+            return Err(BlockError::TimestampTooNew);
+        }
+        Ok(())
+    }
+
+    /// Check the block size and transaction count limits, this does not need the parent
+    pub fn validate_block_limits(&self, block: &Block) -> Result<(), BlockError> {
+        if block.content.data.len() > self.params.max_block_txs {
+            return Err(BlockError::TooManyTransactions);
+        }
+        if block.size() > self.params.max_block_size {
+            return Err(BlockError::BlockTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Check the header, the size limits, and that the merkle root matches the transactions of the block
+    pub fn validate_block(&self, block: &Block, now: u128) -> Result<(), BlockError> {
+        // the parent's state is needed to check the transactions, so its body must be known
+        if block.header.parent != H256::default()
+            && !self.blockchain.contains_key(&block.header.parent)
+        {
+            return Err(BlockError::UnknownParent);
+        }
+        self.validate_header(&block.header, now)?;
+        self.validate_block_limits(block)?;
+        // the merkle tree pads odd levels with their last hash, so repeating the last txs of a
+        // body keeps the root: such a body is a malleated copy of the block, not the block
+        let mut tx_hashes = HashSet::new();
+        if !block
+            .content
+            .data
+            .iter()
+            .all(|tx| tx_hashes.insert(tx.hash()))
+        {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::SignedTransaction;
+
+    // find a nonce solving the puzzle
+    fn solve(block: &mut Block) {
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+    }
+
+    #[test]
+    fn reject_bad_headers() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let now: u128 = 10_000_000_000;
+        let mut block = generate_random_block(&genesis_hash);
+        block.header.difficulty = [255u8; 32].into();
+        block.header.timestamp = now;
+        // the genesis difficulty is expected
+        assert_eq!(
+            blockchain.validate_block(&block, now),
+            Err(BlockError::BadDifficulty)
+        );
+        blockchain.params.retarget_window = 0;
+        block.header.difficulty = blockchain.next_difficulty(&genesis_hash);
+        solve(&mut block);
+        assert_eq!(blockchain.validate_block(&block, now), Ok(()));
+
+        let mut bad_root = block.clone();
+        bad_root.header.merkle_root = [1u8; 32].into();
+        solve(&mut bad_root);
+        assert_eq!(
+            blockchain.validate_block(&bad_root, now),
+            Err(BlockError::BadMerkleRoot)
+        );
+
+        let mut unsolved = block.clone();
+        while unsolved.hash() <= unsolved.header.difficulty {
+            unsolved.header.nonce = unsolved.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(
+            blockchain.validate_block(&unsolved, now),
+            Err(BlockError::BadProofOfWork)
+        );
+
+        // the local clock is far behind the block
+        let early = now - blockchain.params.max_future_block_time - 1;
+        assert_eq!(
+            blockchain.validate_block(&block, early),
+            Err(BlockError::TimestampTooNew)
+        );
+        // genesis timestamp is 0, so the median time past of the genesis is 0
+        let mut old = block.clone();
+        old.header.timestamp = 0;
+        solve(&mut old);
+        assert_eq!(
+            blockchain.validate_block(&old, now),
+            Err(BlockError::TimestampTooOld)
+        );
+
+        let mut orphan = block.clone();
+        orphan.header.parent = [7u8; 32].into();
+        assert_eq!(
+            blockchain.validate_block(&orphan, now),
+            Err(BlockError::UnknownParent)
+        );
+        orphan.header.parent = H256::default();
+        assert_eq!(
+            blockchain.validate_block(&orphan, now),
+            Err(BlockError::NullParent)
+        );
+    }
+    #[test]
+    fn reject_forks_below_finality() {
+        let mut blockchain = Blockchain::new();
+        blockchain.params.finality_depth = 2;
+        let genesis_hash = blockchain.tip();
+        let mut blocks = vec![generate_random_block(&genesis_hash)];
+        for _ in 0..3 {
+            let parent = blocks.last().unwrap().hash();
+            blocks.push(generate_random_block(&parent));
+        }
+        for block in blocks.iter() {
+            blockchain.insert(block);
+        }
+        let now = blocks[3].header.timestamp + 1000;
+        // the tip is at height 4, the blocks up to height 2 are final
+        let below = generate_random_block(&blocks[0].hash());
+        assert_eq!(
+            blockchain.validate_header(&below.header, now),
+            Err(BlockError::ForkBelowFinality)
+        );
+        let mut above = generate_random_block(&blocks[1].hash());
+        above.header.timestamp = now;
+        solve(&mut above);
+        assert_eq!(blockchain.validate_header(&above.header, now), Ok(()));
+        blockchain.insert(&above);
+        // a child of the side branch still forks above the finalized height
+        let mut child = generate_random_block(&above.hash());
+        child.header.timestamp = now + 1;
+        solve(&mut child);
+        assert_eq!(blockchain.validate_header(&child.header, now), Ok(()));
+
+        // a checkpoint at height 3 rejects every other block at that height
+        blockchain.params.finality_depth = 0;
+        blockchain.checkpoints.insert(3, blocks[2].hash());
+        assert_eq!(
+            blockchain.validate_header(&above.header, now),
+            Err(BlockError::CheckpointMismatch)
+        );
+        // and every branch forking below it once the tip reached it
+        assert_eq!(
+            blockchain.validate_header(&child.header, now),
+            Err(BlockError::ForkBelowFinality)
+        );
+    }
+    #[test]
+    fn reject_oversized_block() {
+        let mut blockchain = Blockchain::new();
+        let address = crate::types::address::generate_random_address();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.data = (1..=3)
+            .map(|height| SignedTransaction::new_coinbase(height, address, 0))
+            .collect();
+        assert_eq!(blockchain.validate_block_limits(&block), Ok(()));
+        blockchain.params.max_block_txs = 2;
+        assert_eq!(
+            blockchain.validate_block_limits(&block),
+            Err(BlockError::TooManyTransactions)
+        );
+        blockchain.params.max_block_txs = 3;
+        blockchain.params.max_block_size = block.size() - 1;
+        assert_eq!(
+            blockchain.validate_block_limits(&block),
+            Err(BlockError::BlockTooLarge)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use super::chainspec::ChainSpec;
+use super::validation::BlockError;
+use super::Blockchain;
+use crate::network::worker::{apply_block_txs, check_state_root};
+use crate::types::hash::H256;
+use crate::types::state::State;
+use std::fmt;
+
+//////
+/// VerifyError is the first inconsistency found by verify_chain
+/// height, hash: the block failing the check
+/// reason: why a peer sending this block would have it rejected
+//////
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub height: u128,
+    pub hash: H256,
+    pub reason: BlockError,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block {} at height {}: {}",
+            self.hash, self.height, self.reason
+        )
+    }
+}
+
+impl Blockchain {
+    /// Check the longest chain again from genesis to the tip, nothing inserted is trusted.
+    /// The blocks are replayed on a new blockchain of spec as if they came from a peer:
+    /// parent link, checkpoints, difficulty and proof of work, timestamps, limits and merkle
+    /// root, then the signatures and utxo rules of every tx and the state root, against the
+    /// state replayed from genesis_state. now is the local clock in milliseconds.
+    /// returns the height of the tip once every block passed
+    pub fn verify_chain(
+        &self,
+        spec: &ChainSpec,
+        genesis_state: &State,
+        now: u128,
+    ) -> Result<u128, VerifyError> {
+        let mut replay = Blockchain::from_spec(spec);
+        let mut state = genesis_state.clone();
+        for hash in self.all_blocks_in_longest_chain().into_iter().skip(1) {
+            let height = self.length[&hash];
+            let fail = |reason| VerifyError {
+                height,
+                hash,
+                reason,
+            };
+            // a block below the base of a snapshot has no body to check
+            let block = self
+                .blockchain
+                .get(&hash)
+                .ok_or_else(|| fail(BlockError::MissingBody))?;
+            replay.validate_block(block, now).map_err(fail)?;
+            apply_block_txs(&block.content.data, &replay.params, &mut state)
+                .and_then(|undo| check_state_root(block, &mut state, undo))
+                .map_err(fail)?;
+            replay.insert(block);
+        }
+        Ok(self.longest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::hash::Hashable;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::SignedTransaction;
+
+    // a block paying the full subsidy to the ico address, with the state root of state after it
+    fn mine_child(spec: &ChainSpec, state: &mut State, parent: &H256, timestamp: u128) -> Block {
+        let height = state.height + 1;
+        let mut block = generate_random_block(parent);
+        block.content.data = vec![SignedTransaction::new_coinbase(
+            height,
+            State::ico_address(),
+            spec.params.block_subsidy(height),
+        )];
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        apply_block_txs(&block.content.data, &spec.params, state).unwrap();
+        block.header.state_root = state.state_root();
+        block.header.difficulty = spec.genesis_target;
+        block.header.timestamp = timestamp;
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
+
+    #[test]
+    fn report_first_bad_block() {
+        let mut spec = ChainSpec::default();
+        spec.params.retarget_window = 0;
+        let genesis_state = spec.genesis_state();
+        let mut blockchain = Blockchain::from_spec(&spec);
+        let mut state = genesis_state.clone();
+        let now: u128 = 10_000_000_000;
+        for i in 1..=3 {
+            let block = mine_child(&spec, &mut state, &blockchain.tip(), now - 100 + i);
+            blockchain.insert(&block);
+        }
+        assert_eq!(blockchain.verify_chain(&spec, &genesis_state, now), Ok(3));
+
+        // insert does not check anything, a block committing to a wrong state gets in
+        let mut bad = mine_child(&spec, &mut state, &blockchain.tip(), now - 10);
+        bad.header.state_root = [1u8; 32].into();
+        while bad.hash() > bad.header.difficulty {
+            bad.header.nonce = bad.header.nonce.wrapping_add(1);
+        }
+        blockchain.insert(&bad);
+        let good = mine_child(&spec, &mut state, &bad.hash(), now - 9);
+        blockchain.insert(&good);
+        assert_eq!(
+            blockchain.verify_chain(&spec, &genesis_state, now),
+            Err(VerifyError {
+                height: 4,
+                hash: bad.hash(),
+                reason: BlockError::BadStateRoot,
+            })
+        );
+    }
+
+    #[test]
+    fn report_missing_body() {
+        let mut spec = ChainSpec::default();
+        spec.params.retarget_window = 0;
+        let genesis_state = spec.genesis_state();
+        let mut blockchain = Blockchain::from_spec(&spec);
+        let mut state = genesis_state.clone();
+        let now: u128 = 10_000_000_000;
+        let mut hashes = Vec::new();
+        for i in 1..=3 {
+            let block = mine_child(&spec, &mut state, &blockchain.tip(), now - 100 + i);
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        // the header stays on the longest chain, only the body is gone
+        blockchain.blockchain.remove(&hashes[1]);
+        assert_eq!(
+            blockchain.verify_chain(&spec, &genesis_state, now),
+            Err(VerifyError {
+                height: 2,
+                hash: hashes[1],
+                reason: BlockError::MissingBody,
+            })
+        );
+    }
+}
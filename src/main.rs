@@ -10,16 +10,19 @@ pub mod network;
 pub mod tx_generator;
 pub mod types;
 
-use crate::types::block::Block;
+use crate::types::address::Address;
 use crate::types::hash::H256;
 use crate::types::state::State;
 use api::Server as ApiServer;
+use blockchain::chainfile::ChainFileReader;
+use blockchain::chainspec::ChainSpec;
+use blockchain::snapshot::Snapshot;
 use blockchain::Blockchain;
 use clap::clap_app;
 use log::{error, info};
 use smol::channel;
-use std::collections::HashMap;
 use std::net;
+use std::path;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -35,28 +38,202 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg chain_spec: --("chain-spec") [FILE] "Sets the JSON chain spec defining genesis, ICO allocations and consensus parameters")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory to store blocks in, the chain is reloaded from it at start")
+     (@arg miner_address: --("miner-address") [ADDR] "Sets the hex address the coinbase of mined blocks pays to, defaults to the ICO address")
+     (@arg light: --light "Runs a light node, only syncing headers and proving the txs of watched addresses")
+     (@arg watch: -w --watch ... [ADDR] "Sets the hex addresses a light node watches")
+     (@arg snapshot: --snapshot [FILE] conflicts_with[data_dir light] "Starts from a utxo snapshot file instead of replaying the blocks from genesis")
+     (@arg snapshot_hash: --("snapshot-hash") [HASH] requires[snapshot] "Sets the trusted hash the snapshot must have, otherwise only the state root of its block is checked")
+     (@arg export_snapshot: --("export-snapshot") [FILE] "Writes the utxo set at --snapshot-block to a snapshot file, then exits")
+     (@arg snapshot_block: --("snapshot-block") [HASH] requires[export_snapshot] "Sets the block hash the exported snapshot is taken at, defaults to the tip")
+     (@arg export_chain: --("export-chain") [FILE] "Writes the blocks of the longest chain to a chain file, then exits")
+     (@arg export_tree: --("export-tree") requires[export_chain] "Exports the whole block tree rather than the longest chain")
+     (@arg import: --import [FILE] conflicts_with[light] "Imports the blocks of a chain file at start, checking them like blocks received from peers")
+     (@arg verify: --verify conflicts_with[snapshot] "Checks every block of the longest chain again from genesis, reports the first invalid one, then exits")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
-    let genesis_block_hash = blockchain.tip();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-    let tx_mempool = mempool::Mempool::new();
+    let chain_spec = match matches.value_of("chain_spec") {
+        Some(spec_file) => ChainSpec::load(path::Path::new(spec_file)).unwrap_or_else(|e| {
+            error!("Error loading chain spec {}: {}", spec_file, e);
+            process::exit(1);
+        }),
+        None => ChainSpec::default(),
+    };
+    let genesis_state = chain_spec.genesis_state(); // including ICO
+
+    let (mut blockchain, mut bts_map, mut state) = if let Some(snapshot_file) = matches.value_of("snapshot") {
+        let trusted_hash = matches.value_of("snapshot_hash").map(|hash| {
+            hash.parse::<H256>().unwrap_or_else(|e| {
+                error!("Error parsing snapshot hash: {}", e);
+                process::exit(1);
+            })
+        });
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        // a node started from a snapshot has no block to replay
+        let (blockchain, state) = Snapshot::read(path::Path::new(snapshot_file))
+            .and_then(|snapshot| {
+                Blockchain::from_snapshot(&chain_spec, &snapshot, trusted_hash.as_ref(), now)
+            })
+            .unwrap_or_else(|e| {
+                error!("Error starting from snapshot {}: {}", snapshot_file, e);
+                process::exit(1);
+            });
+        (blockchain, BlockToStateMap::new(), state)
+    } else {
+        let blockchain = match matches.value_of("data_dir") {
+            Some(data_dir) => Blockchain::open(path::Path::new(data_dir), &chain_spec)
+                .unwrap_or_else(|e| {
+                    error!("Error opening data directory {}: {}", data_dir, e);
+                    process::exit(1);
+                }),
+            None => Blockchain::from_spec(&chain_spec),
+        };
+        // replay blocks loaded from disk (only genesis without a data directory)
+        let (bts_map, state) = BlockToStateMap::rebuild(&blockchain, &genesis_state);
+        (blockchain, bts_map, state)
+    };
+    println!(
+        "chain {} with genesis block {}",
+        chain_spec.name,
+        blockchain.ancestor(&blockchain.tip(), 0)
+    );
+
+    // check the chain loaded from the data directory and exit
+    if matches.is_present("verify") {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match blockchain.verify_chain(&chain_spec, &genesis_state, now) {
+            Ok(height) => {
+                println!(
+                    "verified the longest chain up to the tip {} at height {}",
+                    blockchain.tip(),
+                    height
+                );
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Error verifying the chain: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // write the snapshot and exit
+    if let Some(snapshot_file) = matches.value_of("export_snapshot") {
+        let block_hash = match matches.value_of("snapshot_block") {
+            Some(hash) => hash.parse::<H256>().unwrap_or_else(|e| {
+                error!("Error parsing snapshot block: {}", e);
+                process::exit(1);
+            }),
+            None => blockchain.tip(),
+        };
+        if !blockchain.blockchain.contains_key(&block_hash) {
+            error!("Error exporting snapshot: block {} not found", block_hash);
+            process::exit(1);
+        }
+        let block_state = bts_map.state_at(&blockchain, &state, &block_hash);
+        let snapshot = Snapshot::new(&blockchain, &block_hash, &block_state);
+        match snapshot.write(path::Path::new(snapshot_file)) {
+            Ok(hash) => {
+                println!(
+                    "snapshot of block {} at height {} written to {}, hash {}",
+                    block_hash,
+                    snapshot.height(),
+                    snapshot_file,
+                    hash
+                );
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Error writing snapshot {}: {}", snapshot_file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // write the chain file and exit
+    if let Some(chain_file) = matches.value_of("export_chain") {
+        let whole_tree = matches.is_present("export_tree");
+        match blockchain.export_chain(path::Path::new(chain_file), whole_tree) {
+            Ok(count) => {
+                println!("{} blocks written to {}", count, chain_file);
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Error writing chain file {}: {}", chain_file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut tx_mempool = mempool::Mempool::new();
+
+    // replay the blocks of a chain file before joining the network
+    if let Some(chain_file) = matches.value_of("import") {
+        let report = ChainFileReader::open(path::Path::new(chain_file)).and_then(|blocks| {
+            network::worker::import_blocks(
+                blocks,
+                &mut blockchain,
+                &mut tx_mempool,
+                &mut state,
+                &mut bts_map,
+            )
+        });
+        match report {
+            Ok(report) => println!(
+                "imported {} blocks from {}, {} already known, {} rejected, tip {} at height {}",
+                report.imported,
+                chain_file,
+                report.known,
+                report.rejected.len(),
+                blockchain.tip(),
+                blockchain.longest
+            ),
+            Err(e) => {
+                error!("Error reading chain file {}: {}", chain_file, e);
+                process::exit(1);
+            }
+        }
+    }
+
     let tx_mempool = Arc::new(Mutex::new(tx_mempool));
 
-    let orphan_buffer: HashMap<H256, Block> = HashMap::new();
+    let orphan_buffer = network::orphan::OrphanPool::new();
     let orphan_buffer = Arc::new(Mutex::new(orphan_buffer));
 
-    let state = State::new(); // including ICO
-
-    let mut bts_map = BlockToStateMap::new();
-    bts_map.insert(genesis_block_hash, state.clone());
+    let blockchain = Arc::new(Mutex::new(blockchain));
 
     let state = Arc::new(Mutex::new(state));
     let bts_map = Arc::new(Mutex::new(bts_map));
+    let sync = if matches.is_present("light") {
+        let watched: Vec<Address> = matches
+            .values_of("watch")
+            .into_iter()
+            .flatten()
+            .map(|addr| {
+                addr.parse::<Address>().unwrap_or_else(|e| {
+                    error!("Error parsing watched address {}: {}", addr, e);
+                    process::exit(1);
+                })
+            })
+            .collect();
+        println!("light node watching {} addresses", watched.len());
+        network::sync::SyncState::new_light(network::light::WatchList::new(&watched))
+    } else {
+        network::sync::SyncState::new()
+    };
+    let light = sync.is_light();
+    let sync = Arc::new(Mutex::new(sync));
 
     // parse p2p server address
     let p2p_addr = matches
@@ -103,23 +280,38 @@ fn main() {
         &orphan_buffer,
         &state,
         &bts_map,
+        &sync,
     );
     worker_ctx.start();
 
     // start the miner
+    let miner_address = match matches.value_of("miner_address") {
+        Some(addr) => addr.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing miner address: {}", e);
+            process::exit(1);
+        }),
+        None => State::ico_address(),
+    };
     let (miner_ctx, miner, finished_block_chan) =
-        miner::new(&blockchain, &tx_mempool, &state, &bts_map);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
+        miner::new(&blockchain, &tx_mempool, &state, &bts_map, miner_address);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan);
 
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    // a light node has no state to mine or make transactions on
+    if !light {
+        miner_ctx.start();
+        miner_worker_ctx.start();
+    }
 
     // start tx_generator
-    let (tx_gen_ctx, tx_gen, tx_to_send) = tx_generator::new(&tx_mempool, &state);
+    let coinbase_maturity = blockchain.lock().unwrap().params.coinbase_maturity;
+    let (tx_gen_ctx, tx_gen, tx_to_send) =
+        tx_generator::new(&tx_mempool, &state, coinbase_maturity);
     let tx_gen_worker_ctx = tx_generator::worker::Worker::new(&server, tx_to_send, &tx_mempool);
 
-    tx_gen_ctx.start();
-    tx_gen_worker_ctx.start();
+    if !light {
+        tx_gen_ctx.start();
+        tx_gen_worker_ctx.start();
+    }
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
@@ -155,7 +347,17 @@ fn main() {
     }
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain, &tx_gen, &tx_mempool, &state, &bts_map);
+    ApiServer::start(
+        api_addr,
+        &miner,
+        &server,
+        &blockchain,
+        &tx_gen,
+        &tx_mempool,
+        &state,
+        &bts_map,
+        &sync,
+    );
 
     loop {
         std::thread::park();
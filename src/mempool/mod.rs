@@ -10,12 +10,14 @@ use std::collections::{HashMap, HashSet};
 /// tx_evidence is to store previously existing tx_hash, primary reason for this is to prevent same tx from appearing in mempool again
 /// spent_tx_in is for double spend prevention, it keep track of tx_in already used, either from new block or from previous tx in mempool
 /// if we want to insert tx into mempool, we check all its tx_in. If any of the tx_in is in spent_tx_in, insert is rejected.
+/// version counts the changes of tx_map, so the miner knows when to select txs again
 /////
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Mempool {
     pub tx_evidence: HashSet<H256>,
     pub tx_map: HashMap<H256, SignedTransaction>,
     pub spent_tx_in: HashMap<(H256, u8), H256>, // for double spend prevention (tx) (pre_tx, index): cur_tx_hash
+    pub version: u64,
 }
 impl Mempool {
     pub fn new() -> Self {
@@ -23,6 +25,7 @@ impl Mempool {
             tx_evidence: HashSet::new(),
             tx_map: HashMap::new(),
             spent_tx_in: HashMap::new(),
+            version: 0,
         }
     }
 
@@ -65,6 +68,7 @@ impl Mempool {
         println!("{:?}", tx.transaction);
         self.tx_map.insert(tx_hash, tx.clone());
         self.tx_evidence.insert(tx_hash);
+        self.version += 1;
         true
     }
 
@@ -72,13 +76,57 @@ impl Mempool {
         let tx_hash: H256 = transaction.hash();
         if self.tx_map.contains_key(&tx_hash) {
             self.tx_map.remove(&tx_hash);
+            self.version += 1;
             // self.tx_evidence.remove(&tx_hash);
         }
     }
+    // remove the txs of a new block from mempool,
+    // drop any tx in mempool that double spends a tx_in used by the block,
+    // then mark the block's tx_in as spent in spent_tx_in
+    pub fn remove_block_txs(&mut self, block_txs: &[SignedTransaction]) {
+        for tx in block_txs {
+            self.remove(tx);
+            let tx_hash = tx.hash();
+            for tx_in in tx.transaction.tx_input.iter() {
+                let key = (tx_in.previous_output, tx_in.index);
+                if let Some(conflict_hash) = self.spent_tx_in.get(&key) {
+                    // remove tx in mempool using hash
+                    let conflict_hash = *conflict_hash;
+                    self.remove_with_hash(conflict_hash);
+                }
+                // mark tx_in as spent in spent_tx_in
+                self.spent_tx_in.insert(key, tx_hash);
+            }
+        }
+    }
+    // the block containing these txs was disconnected from the longest chain,
+    // release the tx_in they marked as spent so they (or conflicting txs) can enter mempool again
+    pub fn unspend_block_txs(&mut self, block_txs: &[SignedTransaction]) {
+        for tx in block_txs {
+            let tx_hash = tx.hash();
+            for tx_in in tx.transaction.tx_input.iter() {
+                let key = (tx_in.previous_output, tx_in.index);
+                if self.spent_tx_in.get(&key) == Some(&tx_hash) {
+                    self.spent_tx_in.remove(&key);
+                }
+            }
+        }
+    }
+    // put back a tx from a disconnected block, it is allowed even if tx_evidence has seen it
+    pub fn reinsert(&mut self, tx: &SignedTransaction) -> bool {
+        self.tx_evidence.remove(&tx.hash());
+        if self.insert(tx) {
+            return true;
+        }
+        // keep remembering the tx so it is not requested from peers again
+        self.tx_evidence.insert(tx.hash());
+        false
+    }
     // remove using tx hash
     pub fn remove_with_hash(&mut self, tx_hash: H256) {
         if self.tx_map.contains_key(&tx_hash) {
             self.tx_map.remove(&tx_hash);
+            self.version += 1;
             // self.tx_evidence.remove(&tx_hash);
         }
     }
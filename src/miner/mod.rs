@@ -1,15 +1,19 @@
 pub mod worker;
 
-use log::info;
+use log::{error, info};
 
+use crate::blockchain::validation::BlockError;
 use crate::mempool::Mempool;
+use crate::network::worker::{apply_block_txs, process_block};
+use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::block::Content;
 use crate::types::block::Header;
-use crate::types::hash::Hashable;
+use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
 use crate::types::state::BlockToStateMap;
 use crate::types::state::State;
+use crate::types::transaction::SignedTransaction;
 use crate::Blockchain;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use rand::Rng;
@@ -39,6 +43,24 @@ pub struct Context {
     tx_mempool: Arc<Mutex<Mempool>>,
     state: Arc<Mutex<State>>,
     bts_map: Arc<Mutex<BlockToStateMap>>,
+    miner_address: Address,
+    /// the block being mined, rebuilt when the tip or the mempool changes
+    template: Option<BlockTemplate>,
+    /// (tip, mempool version) of the last template that could not be built
+    failed_template: Option<(H256, u64)>,
+}
+
+/// BlockTemplate is the block being mined without its nonce and timestamp
+/// mempool_version: version of the mempool the txs were selected from
+/// min_timestamp: the timestamp must be above the median time past of the parent
+struct BlockTemplate {
+    parent: H256,
+    mempool_version: u64,
+    difficulty: H256,
+    min_timestamp: u128,
+    content: Content,
+    merkle_root: H256,
+    state_root: H256,
 }
 
 #[derive(Clone)]
@@ -52,6 +74,7 @@ pub fn new(
     tx_mempool: &Arc<Mutex<Mempool>>,
     state: &Arc<Mutex<State>>,
     bts_map: &Arc<Mutex<BlockToStateMap>>,
+    miner_address: Address,
 ) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
@@ -64,6 +87,9 @@ pub fn new(
         tx_mempool: Arc::clone(tx_mempool),
         state: Arc::clone(state),
         bts_map: Arc::clone(bts_map),
+        miner_address,
+        template: None,
+        failed_template: None,
     };
 
     let handle = Handle {
@@ -80,10 +106,16 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     let tx_mempool = Mempool::new();
     let tx_mempool = Arc::new(Mutex::new(tx_mempool));
     let state = State::new();
-    let state = Arc::new(Mutex::new(state));
     let bts_map = BlockToStateMap::new();
+    let state = Arc::new(Mutex::new(state));
     let bts_map = Arc::new(Mutex::new(bts_map));
-    new(&blockchain, &tx_mempool, &state, &bts_map)
+    new(
+        &blockchain,
+        &tx_mempool,
+        &state,
+        &bts_map,
+        crate::types::address::generate_random_address(),
+    )
 }
 
 impl Handle {
@@ -115,8 +147,6 @@ impl Context {
 
     fn miner_loop(&mut self) {
         // main mining loop
-        // block: 50 txs
-        let block_tx_num_limit = 50;
         loop {
             // check and react to control signals
             match self.operating_state {
@@ -168,89 +198,83 @@ impl Context {
             let mut state_with_lock = self.state.lock().unwrap();
             let mut bts_map_with_lock = self.bts_map.lock().unwrap();
             let parent_hash = blockchain_with_lock.tip;
-            // mining: create random nonce
-            let mut rng = rand::thread_rng();
-            let new_nonce: u32 = rng.gen();
-
-            let difficulty = blockchain_with_lock.blockchain[&parent_hash]
-                .header
-                .difficulty;
-            let timestamp: u128 = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-
-            let mut transactions = Vec::new();
-            let mut block_tx_num = 0; //keep track of how many tx are selected
-
-            // if mempool no enough tx to process
-            // it has to be here so that there will be at least one tx in a block
-            if mempool_with_lock.tx_map.len() < 1 {
-                continue;
-            }
-            // select txs from mempool
-            for (_tx_key, tx) in mempool_with_lock.tx_map.iter() {
-                if block_tx_num + 1 > block_tx_num_limit {
-                    break;
+            // the txs only change with the tip or the mempool, the template is built once for
+            // them and every attempt only changes the nonce and the timestamp
+            let stale = match self.template.as_ref() {
+                Some(template) => {
+                    template.parent != parent_hash
+                        || template.mempool_version != mempool_with_lock.version
                 }
-                transactions.push(tx.clone());
-                block_tx_num += 1;
-            }
-
-            //create merkle root
-            let merkle_tree = MerkleTree::new(transactions.as_ref());
-            let merkle_root = merkle_tree.root();
-
-            // create empty content
-            let content: Content = Content { data: transactions };
-
-            let header = Header {
-                parent: parent_hash,
-                nonce: new_nonce,
-                difficulty: difficulty,
-                timestamp: timestamp,
-                merkle_root: merkle_root,
-            };
-            // create block to be mined
-            let block = Block {
-                header: header,
-                content: content,
+                None => true,
             };
-
-            // Check whether the proof-of-work hash puzzle is solved or not.
-            if block.hash() <= difficulty {
-                println!("Successfully mined a block {:?}", block);
-
-                // remove used tx in mempool, update state
-                for tx in block.clone().content.data {
-                    mempool_with_lock.remove(&tx);
-                    state_with_lock.update(&tx);
-
-                    // remove any double spend tx_in in mempool found in block,
-                    // add tx_in in block to spent_tx_in
-                    for tx_in in tx.clone().transaction.tx_input {
-                        if mempool_with_lock
-                            .spent_tx_in
-                            .contains_key(&(tx_in.previous_output, tx_in.index))
-                        {
-                            // remove tx in mempool using hash
-                            let tx_hash = mempool_with_lock.spent_tx_in
-                                [&(tx_in.previous_output, tx_in.index)];
-                            mempool_with_lock.remove_with_hash(tx_hash);
+            // a template that failed is only built again once the tip or the mempool changed
+            let template_key = (parent_hash, mempool_with_lock.version);
+            if stale {
+                self.template = None;
+                if self.failed_template != Some(template_key) {
+                    match self.build_template(
+                        &blockchain_with_lock,
+                        &mempool_with_lock,
+                        &mut state_with_lock,
+                    ) {
+                        Ok(template) => self.template = Some(template),
+                        Err(e) => {
+                            // select_transactions and apply_block_txs disagree: drop the tx
+                            // so the next template leaves it out, or wait for a change
+                            error!("Error building the block template: {}", e);
+                            match e {
+                                BlockError::InvalidTransaction(tx_hash)
+                                    if mempool_with_lock.tx_map.contains_key(&tx_hash) =>
+                                {
+                                    mempool_with_lock.remove_with_hash(tx_hash)
+                                }
+                                _ => self.failed_template = Some(template_key),
+                            }
                         }
-                        // mark tx_in as spent in spent_tx_in
-                        mempool_with_lock
-                            .spent_tx_in
-                            .insert((tx_in.previous_output, tx_in.index), tx.hash());
                     }
                 }
-                //insert into block-to-state-map
-                bts_map_with_lock.insert(block.hash(), state_with_lock.clone());
-                //insert into blockchain
-                blockchain_with_lock.insert(&block);
-                self.finished_block_chan
-                    .send(block.clone())
-                    .expect("Send finished block error");
+            }
+            if let Some(template) = self.template.as_ref() {
+                // mining: create random nonce
+                let mut rng = rand::thread_rng();
+                let new_nonce: u32 = rng.gen();
+                // timestamp must be after the median time past of the parent
+                let timestamp: u128 = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+                    .max(template.min_timestamp);
+                let header = Header {
+                    parent: parent_hash,
+                    nonce: new_nonce,
+                    difficulty: template.difficulty,
+                    timestamp,
+                    merkle_root: template.merkle_root,
+                    state_root: template.state_root,
+                };
+
+                // Check whether the proof-of-work hash puzzle is solved or not.
+                // validate against the parent's state, update mempool, state and blockchain
+                if header.hash() <= template.difficulty {
+                    let block = Block {
+                        header,
+                        content: template.content.clone(),
+                    };
+                    if process_block(
+                        &block,
+                        &mut blockchain_with_lock,
+                        &mut mempool_with_lock,
+                        &mut state_with_lock,
+                        &mut bts_map_with_lock,
+                    )
+                    .is_ok()
+                    {
+                        println!("Successfully mined a block {:?}", block);
+                        self.finished_block_chan
+                            .send(block.clone())
+                            .expect("Send finished block error");
+                    }
+                }
             }
             std::mem::drop(blockchain_with_lock);
             std::mem::drop(mempool_with_lock);
@@ -265,6 +289,118 @@ impl Context {
             }
         }
     }
+
+    /// Build the template of a block on the tip: the coinbase and the mempool txs selected by
+    /// fee rate, with their merkle root and the state root after them
+    /// returns the error of apply_block_txs if the selected txs do not apply to the tip state
+    fn build_template(
+        &self,
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+        state: &mut State,
+    ) -> Result<BlockTemplate, BlockError> {
+        let parent_hash = blockchain.tip;
+        let difficulty = blockchain.next_difficulty(&parent_hash);
+        // the coinbase goes first and collects the subsidy and the fees
+        let height = state.height + 1;
+        let coinbase = SignedTransaction::new_coinbase(height, self.miner_address, 0);
+        // the size of the header, the coinbase and the tx list length does not depend on
+        // the values in them, what is left of the limit can be filled with mempool txs
+        let base_size = Block {
+            header: Header {
+                parent: parent_hash,
+                nonce: 0,
+                difficulty,
+                timestamp: 0,
+                merkle_root: H256::default(),
+                state_root: H256::default(),
+            },
+            content: Content {
+                data: vec![coinbase],
+            },
+        }
+        .size();
+        let params = &blockchain.params;
+        let (mut transactions, fees) = select_transactions(
+            mempool,
+            state,
+            params.coinbase_maturity,
+            params.max_block_size.saturating_sub(base_size),
+            params.max_block_txs.saturating_sub(1),
+        );
+        let reward = params.block_subsidy(height).saturating_add(fees);
+        transactions.insert(
+            0,
+            SignedTransaction::new_coinbase(height, self.miner_address, reward),
+        );
+        let merkle_root = MerkleTree::new(transactions.as_ref()).root();
+
+        // apply the txs to the tip state to get the state after the block, then take them back
+        let undo = apply_block_txs(&transactions, params, state)?;
+        let state_root = state.state_root();
+        state.revert_undo(&undo);
+        Ok(BlockTemplate {
+            parent: parent_hash,
+            mempool_version: mempool.version,
+            difficulty,
+            min_timestamp: blockchain.median_time_past(&parent_hash) + 1,
+            content: Content { data: transactions },
+            merkle_root,
+            state_root,
+        })
+    }
+}
+
+/// Pick the mempool txs that can be included in the next block, by decreasing fee rate
+/// (fee per byte), until their total size reaches max_bytes or max_txs txs are selected.
+/// Txs whose inputs are not unspent and mature in state (the tip state) are left out.
+/// returns the selected txs and the sum of their fees
+pub fn select_transactions(
+    mempool: &Mempool,
+    state: &State,
+    coinbase_maturity: u128,
+    max_bytes: u64,
+    max_txs: usize,
+) -> (Vec<SignedTransaction>, u64) {
+    // (fee, size, hash, tx) of every spendable tx
+    let mut candidates: Vec<(u64, u64, &H256, &SignedTransaction)> = Vec::new();
+    for (tx_hash, tx) in mempool.tx_map.iter() {
+        let spendable = tx.transaction.tx_input.iter().all(|tx_in| {
+            state
+                .utxo
+                .contains_key(&(tx_in.previous_output, tx_in.index))
+                && state.is_mature(&tx_in.previous_output, coinbase_maturity)
+        });
+        if !spendable {
+            continue;
+        }
+        if let Some(fee) = state.fee(tx) {
+            candidates.push((fee, tx.size(), tx_hash, tx));
+        }
+    }
+    // compare fee_a / size_a with fee_b / size_b without rounding, ties by hash to be deterministic
+    candidates.sort_by(|(fee_a, size_a, hash_a, _), (fee_b, size_b, hash_b, _)| {
+        (*fee_b as u128 * *size_a as u128)
+            .cmp(&(*fee_a as u128 * *size_b as u128))
+            .then_with(|| hash_a.cmp(hash_b))
+    });
+
+    let mut transactions = Vec::new();
+    let mut total_size: u64 = 0;
+    let mut fees: u64 = 0;
+    for (fee, size, _, tx) in candidates {
+        if transactions.len() >= max_txs {
+            break;
+        }
+        // a smaller tx further down may still fit
+        if total_size + size > max_bytes {
+            continue;
+        }
+        total_size += size;
+        fees = fees.saturating_add(fee);
+        transactions.push(tx.clone());
+    }
+    (transactions, fees)
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -287,6 +423,110 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_past_invalid_mempool_tx() {
+        use crate::types::state::State;
+        use crate::types::transaction::signed_spend;
+
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        let mempool = miner_ctx.tx_mempool.clone();
+        // a spend of the ICO with a broken signature passes the mempool but not the block
+        let ico_output = *State::new().utxo.keys().next().unwrap();
+        let mut bad_tx = signed_spend(&State::ico_key(), ico_output.0, ico_output.1, 1);
+        bad_tx.signature[0] ^= 1;
+        assert!(mempool.lock().unwrap().insert(&bad_tx));
+        miner_ctx.start();
+        miner_handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.content.data.len(), 1);
+        assert!(!mempool.lock().unwrap().tx_map.contains_key(&bad_tx.hash()));
+    }
+
+    #[test]
+    fn select_by_fee_rate() {
+        use super::select_transactions;
+        use crate::mempool::Mempool;
+        use crate::types::state::State;
+        use crate::types::transaction::signed_spend;
+
+        let key = State::ico_key();
+        let mut state = State::new();
+        let (ico_output, ico_value) = state.utxo.iter().next().map(|(k, v)| (k.0, *v)).unwrap();
+        // split the ICO in three outputs, spend each with a different fee
+        for index in 1..3 {
+            state.utxo.insert((ico_output, index), ico_value);
+        }
+        let spend = |index, fee| signed_spend(&key, ico_output, index, ico_value.0 - fee);
+        let mut mempool = Mempool::new();
+        let (low, high, mid) = (spend(0, 1), spend(1, 100), spend(2, 10));
+        for tx in [&low, &high, &mid].iter() {
+            assert!(mempool.insert(tx));
+        }
+
+        let (selected, fees) = select_transactions(&mempool, &state, 0, u64::MAX, usize::MAX);
+        let selected: Vec<_> = selected.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![high.hash(), mid.hash(), low.hash()]);
+        assert_eq!(fees, 111);
+
+        // room for two txs only
+        let (selected, fees) =
+            select_transactions(&mempool, &state, 0, 2 * high.size(), usize::MAX);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(fees, 110);
+        let (selected, fees) = select_transactions(&mempool, &state, 0, u64::MAX, 1);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(fees, 100);
+    }
+
+    #[test]
+    fn template_follows_mempool() {
+        use crate::types::state::State;
+        use crate::types::transaction::signed_spend;
+
+        let (miner_ctx, _miner_handle, _finished_block_chan) = super::test_new();
+        let blockchain = miner_ctx.blockchain.lock().unwrap();
+        let mut mempool = miner_ctx.tx_mempool.lock().unwrap();
+        let mut state = miner_ctx.state.lock().unwrap();
+        let template = miner_ctx
+            .build_template(&blockchain, &mempool, &mut state)
+            .unwrap();
+        assert_eq!(template.parent, blockchain.tip());
+        assert_eq!(template.content.data.len(), 1);
+        let state_root = state.state_root();
+        assert_ne!(template.state_root, state_root);
+
+        // a new tx changes the mempool version, the next template includes it
+        let (ico_output, ico_value) = state.utxo.iter().next().map(|(k, v)| (*k, *v)).unwrap();
+        let tx = signed_spend(
+            &State::ico_key(),
+            ico_output.0,
+            ico_output.1,
+            ico_value.0 - 1,
+        );
+        assert!(mempool.insert(&tx));
+        assert_ne!(mempool.version, template.mempool_version);
+        let template = miner_ctx
+            .build_template(&blockchain, &mempool, &mut state)
+            .unwrap();
+        assert_eq!(template.mempool_version, mempool.version);
+        assert_eq!(template.content.data[1].hash(), tx.hash());
+        // building the template leaves the tip state untouched
+        assert_eq!(state.state_root(), state_root);
+
+        // a selected tx that does not apply is an error, not a panic
+        let mut bad_tx = tx.clone();
+        bad_tx.signature[0] ^= 1;
+        mempool.remove(&tx);
+        mempool.tx_evidence.clear();
+        mempool.spent_tx_in.clear();
+        assert!(mempool.insert(&bad_tx));
+        assert!(miner_ctx
+            .build_template(&blockchain, &mempool, &mut state)
+            .is_err());
+        assert_eq!(state.state_root(), state_root);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
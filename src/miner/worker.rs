@@ -2,29 +2,21 @@ use crate::network::message::Message;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
-use crate::Blockchain;
 use crossbeam::channel::Receiver;
 use log::info;
-use std::sync::{Arc, Mutex};
 use std::thread;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
-    blockchain: Arc<Mutex<Blockchain>>,
 }
 
 impl Worker {
-    pub fn new(
-        server: &ServerHandle,
-        finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>,
-    ) -> Self {
+    pub fn new(server: &ServerHandle, finished_block_chan: Receiver<Block>) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
-            blockchain: Arc::clone(blockchain),
         }
     }
 
@@ -44,13 +36,10 @@ impl Worker {
                 .finished_block_chan
                 .recv()
                 .expect("Receive finished block error");
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            let mut blockchain_with_lock = self.blockchain.lock().unwrap();
-            blockchain_with_lock.insert(&_block);
-
+            // the miner already validated and inserted this block (along with its state),
+            // only broadcast this block hash
             self.server
                 .broadcast(Message::NewBlockHashes(vec![_block.hash()]));
-            std::mem::drop(blockchain_with_lock);
         }
     }
 }
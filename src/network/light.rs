@@ -0,0 +1,158 @@
+use crate::blockchain::spv::TxProof;
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+// most tx hashes kept for one address, a full peer sends the most recent ones
+pub const MAX_TXS_PER_ADDRESS: usize = 1000;
+
+//////
+/// WatchList is what a light node follows on top of the header chain.
+/// A light node never downloads bodies: it asks full peers for the txs of the watched addresses,
+/// then for a merkle proof of each tx, checked against its own headers.
+/// watched: addresses followed
+/// address_txs: address -> txs full peers reported for it
+/// proven: tx hash -> block hash of txs whose proof was checked
+/// asked: txs whose proof was asked and not received yet
+/// asked_peers: peers sent GetAddressTxs that did not answer yet, replies from others are ignored
+//////
+#[derive(Debug, Default)]
+pub struct WatchList {
+    pub watched: HashSet<Address>,
+    pub address_txs: HashMap<Address, HashSet<H256>>,
+    pub proven: HashMap<H256, H256>,
+    pub asked: HashSet<H256>,
+    pub asked_peers: HashSet<SocketAddr>,
+}
+
+impl WatchList {
+    pub fn new(watched: &[Address]) -> Self {
+        WatchList {
+            watched: watched.iter().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the txs peer reported for the watched addresses, at most MAX_TXS_PER_ADDRESS each
+    /// returns false if the peer was not asked, then nothing is recorded
+    pub fn add_address_txs(
+        &mut self,
+        peer: &SocketAddr,
+        address_txs: &[(Address, Vec<H256>)],
+    ) -> bool {
+        if !self.asked_peers.remove(peer) {
+            return false;
+        }
+        for (address, tx_hashes) in address_txs.iter() {
+            if self.watched.contains(address) {
+                let known = self.address_txs.entry(*address).or_default();
+                for tx_hash in tx_hashes.iter() {
+                    if known.len() >= MAX_TXS_PER_ADDRESS {
+                        break;
+                    }
+                    known.insert(*tx_hash);
+                }
+            }
+        }
+        true
+    }
+
+    /// Txs to ask a proof for: never proven, or proven in a block that left the best header
+    /// chain, and not already asked
+    pub fn wanted_proofs(&self, blockchain: &Blockchain) -> Vec<H256> {
+        let mut wanted: Vec<H256> = self
+            .address_txs
+            .values()
+            .flatten()
+            .filter(|tx_hash| !self.asked.contains(tx_hash))
+            .filter(|tx_hash| match self.proven.get(tx_hash) {
+                Some(block_hash) => !blockchain.is_in_best_header_chain(block_hash),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        wanted.sort();
+        wanted.dedup();
+        wanted
+    }
+
+    /// Check a proof received from a peer against the header chain
+    /// returns whether the tx is now proven
+    pub fn add_proof(&mut self, blockchain: &Blockchain, proof: &TxProof) -> bool {
+        self.asked.remove(&proof.tx_hash);
+        match blockchain.verify_tx_proof(proof) {
+            Ok(_) => {
+                self.proven.insert(proof.tx_hash, proof.header.hash());
+                true
+            }
+            Err(e) => {
+                println!("fail proof check of tx {}: {}", proof.tx_hash, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_random_block;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::SignedTransaction;
+
+    #[test]
+    fn prove_watched_txs() {
+        let mut full = Blockchain::new();
+        let mut light = Blockchain::new();
+        let genesis_hash = full.tip();
+        let address = generate_random_address();
+        let mut block = generate_random_block(&genesis_hash);
+        block.content.data = vec![SignedTransaction::new_coinbase(1, address, 5)];
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        full.insert(&block);
+        light.insert_header(&block.header);
+        let tx_hash = block.content.data[0].hash();
+
+        let mut watch_list = WatchList::new(&[address]);
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        // a reply nobody asked for is ignored
+        assert!(!watch_list.add_address_txs(&peer, &[(address, vec![tx_hash])]));
+        assert!(watch_list.address_txs.is_empty());
+        // txs of addresses not watched are ignored
+        watch_list.asked_peers.insert(peer);
+        assert!(watch_list.add_address_txs(
+            &peer,
+            &[
+                (address, vec![tx_hash]),
+                (generate_random_address(), vec![[3u8; 32].into()]),
+            ]
+        ));
+        // the peer answered, a second reply is ignored
+        assert!(!watch_list.add_address_txs(&peer, &[(address, vec![[4u8; 32].into()])]));
+        assert_eq!(watch_list.wanted_proofs(&light), vec![tx_hash]);
+        watch_list.asked.insert(tx_hash);
+        assert!(watch_list.wanted_proofs(&light).is_empty());
+
+        assert!(watch_list.add_proof(&light, &full.tx_proof(&tx_hash).unwrap()));
+        assert!(watch_list.asked.is_empty());
+        assert!(watch_list.wanted_proofs(&light).is_empty());
+
+        // the block leaves the best header chain, the tx must be proven again
+        let other = generate_random_block(&genesis_hash);
+        let other_child = generate_random_block(&other.hash());
+        light.insert_header(&other.header);
+        light.insert_header(&other_child.header);
+        assert_eq!(watch_list.wanted_proofs(&light), vec![tx_hash]);
+
+        // an address can not be flooded with tx hashes
+        let flood: Vec<H256> = (0..MAX_TXS_PER_ADDRESS + 10)
+            .map(|_| crate::types::hash::generate_random_hash())
+            .collect();
+        watch_list.asked_peers.insert(peer);
+        assert!(watch_list.add_address_txs(&peer, &[(address, flood)]));
+        assert_eq!(watch_list.address_txs[&address].len(), MAX_TXS_PER_ADDRESS);
+    }
+}
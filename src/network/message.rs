@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::blockchain::spv::TxProof;
+use crate::types::{address::Address, hash::H256, block::{Block, Header}, transaction::SignedTransaction};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +13,18 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// (block locator, stop hash): ask for the headers following the locator, up to stop
+    GetHeaders(Vec<H256>, H256),
+    Headers(Vec<Header>),
+    /// sent to a new peer on connection, it answers with its Status
+    GetStatus,
+    /// (tip hash, tip height) of the sender
+    Status(H256, u128),
+    /// ask the proofs that these txs are in the sender's longest chain
+    GetMerkleProof(Vec<H256>),
+    /// proofs of the txs found in the longest chain, unknown txs are left out
+    MerkleProof(Vec<TxProof>),
+    /// ask the txs of the sender's longest chain paying or spending these addresses
+    GetAddressTxs(Vec<Address>),
+    AddressTxs(Vec<(Address, Vec<H256>)>),
 }
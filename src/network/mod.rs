@@ -1,4 +1,7 @@
+pub mod light;
 pub mod message;
+pub mod orphan;
 pub mod peer;
 pub mod server;
+pub mod sync;
 pub mod worker;
@@ -0,0 +1,198 @@
+use super::sync::DOWNLOAD_WINDOW;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// most orphans kept at once
+const MAX_ORPHANS: usize = 2 * DOWNLOAD_WINDOW;
+// most orphans kept from one peer, so a single peer can not fill the pool. Every body asked
+// during sync may arrive before its parent, so one peer may fill the download window
+const MAX_ORPHANS_PER_PEER: usize = DOWNLOAD_WINDOW;
+// orphans whose parent does not arrive within this time are dropped
+const MAX_ORPHAN_AGE: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    peer: SocketAddr,
+    received: Instant,
+}
+
+//////
+/// OrphanPool keeps blocks whose parent block is not known yet, until the parent arrives.
+/// orphans: block hash -> the orphan with the peer that sent it and when
+/// children: parent hash -> hashes of the orphans waiting for this parent
+/// per_peer: number of orphans sent by each peer
+/// When the pool or a peer's share is full, the oldest orphan (of that peer) is evicted.
+//////
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    children: HashMap<H256, Vec<H256>>,
+    per_peer: HashMap<SocketAddr, usize>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, block_hash: &H256) -> bool {
+        self.orphans.contains_key(block_hash)
+    }
+
+    pub fn get(&self, block_hash: &H256) -> Option<&Block> {
+        self.orphans.get(block_hash).map(|orphan| &orphan.block)
+    }
+
+    /// Number of orphans currently kept from peer
+    pub fn peer_count(&self, peer: &SocketAddr) -> usize {
+        self.per_peer.get(peer).cloned().unwrap_or(0)
+    }
+
+    /// Add a block received from peer whose parent is unknown.
+    /// returns false if the block was already in the pool
+    pub fn insert(&mut self, block: Block, peer: SocketAddr) -> bool {
+        let block_hash = block.hash();
+        if self.contains(&block_hash) {
+            return false;
+        }
+        self.expire(Instant::now());
+        if self.peer_count(&peer) >= MAX_ORPHANS_PER_PEER {
+            self.evict_oldest(Some(&peer));
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            self.evict_oldest(None);
+        }
+        self.children
+            .entry(block.header.parent)
+            .or_default()
+            .push(block_hash);
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        self.orphans.insert(
+            block_hash,
+            Orphan {
+                block,
+                peer,
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    pub fn remove(&mut self, block_hash: &H256) -> Option<Block> {
+        let orphan = self.orphans.remove(block_hash)?;
+        let parent = orphan.block.header.parent;
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|hash| hash != block_hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        Some(orphan.block)
+    }
+
+    /// Take out every orphan waiting for parent_hash, it just arrived
+    pub fn remove_children(&mut self, parent_hash: &H256) -> Vec<Block> {
+        let child_hashes = self.children.remove(parent_hash).unwrap_or_default();
+        child_hashes
+            .iter()
+            .filter_map(|child_hash| self.remove(child_hash))
+            .collect()
+    }
+
+    /// Drop the orphans received more than MAX_ORPHAN_AGE before now
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received) > MAX_ORPHAN_AGE)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    // drop the oldest orphan, only looking at the orphans of peer if given
+    fn evict_oldest(&mut self, peer: Option<&SocketAddr>) {
+        let oldest = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| match peer {
+                Some(peer) => orphan.peer == *peer,
+                None => true,
+            })
+            .min_by_key(|(_, orphan)| orphan.received)
+            .map(|(hash, _)| *hash);
+        if let Some(hash) = oldest {
+            self.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn siblings_limits_and_expiry() {
+        let mut pool = OrphanPool::new();
+        let parent = generate_random_hash();
+        let block_a = generate_random_block(&parent);
+        let block_b = generate_random_block(&parent);
+        let block_c = generate_random_block(&block_a.hash());
+        assert!(pool.insert(block_a.clone(), peer(1)));
+        assert!(pool.insert(block_b.clone(), peer(2)));
+        assert!(pool.insert(block_c.clone(), peer(2)));
+        assert!(!pool.insert(block_a.clone(), peer(2)));
+        assert_eq!(pool.peer_count(&peer(2)), 2);
+
+        // two orphans with the same parent are both kept
+        let mut children: Vec<H256> = pool
+            .remove_children(&parent)
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        children.sort();
+        let mut expected = vec![block_a.hash(), block_b.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.peer_count(&peer(1)), 0);
+        assert_eq!(pool.remove_children(&block_a.hash()).len(), 1);
+        assert!(pool.is_empty());
+
+        // one peer only evicts its own orphans
+        pool.insert(block_a.clone(), peer(1));
+        for _ in 0..MAX_ORPHANS_PER_PEER + 10 {
+            pool.insert(generate_random_block(&generate_random_hash()), peer(2));
+        }
+        assert_eq!(pool.peer_count(&peer(2)), MAX_ORPHANS_PER_PEER);
+        assert!(pool.contains(&block_a.hash()));
+
+        pool.expire(Instant::now() + MAX_ORPHAN_AGE + Duration::from_secs(1));
+        assert!(pool.is_empty());
+    }
+}
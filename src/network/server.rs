@@ -224,6 +224,9 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        // both sides of a new connection ask for the other's tip, the one behind starts syncing
+        let mut status_handle = handle.clone();
+        status_handle.write(message::Message::GetStatus);
         Ok(handle)
     }
 }
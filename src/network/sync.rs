@@ -0,0 +1,148 @@
+use super::light::WatchList;
+use super::message::Message;
+use super::peer;
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// most headers sent in one Headers message, a full message means the peer has more
+pub const MAX_HEADERS: usize = 2000;
+// bodies asked from one peer in one GetBlocks message
+const BATCH_SIZE: usize = 16;
+// bodies asked and not received yet, over all peers
+const MAX_IN_FLIGHT: usize = 128;
+// only bodies this close to the tip are asked, so out of order bodies waiting in the
+// orphan buffer stay bounded
+pub const DOWNLOAD_WINDOW: usize = 2 * MAX_IN_FLIGHT;
+// a body not received after this long is asked again, from another peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//////
+/// SyncState drives headers-first synchronization.
+/// The header chain is downloaded first with GetHeaders, validated and kept in the header tree of
+/// Blockchain. The bodies of the best header chain are then asked in batches of GetBlocks,
+/// spread over every peer that served headers, and connected in order as they arrive.
+/// peers: peers that served headers, so they can serve the bodies
+/// in_flight: block hash -> (peer asked, time asked) of bodies not received yet
+/// peer_heights: tip height each peer reported in its Status
+/// light: the watched addresses of a light node, which only syncs headers. None for a full node
+//////
+#[derive(Default)]
+pub struct SyncState {
+    pub peers: HashMap<SocketAddr, peer::Handle>,
+    pub in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    pub peer_heights: HashMap<SocketAddr, u128>,
+    pub light: Option<WatchList>,
+    next_peer: usize,
+}
+
+//////
+/// SyncProgress is the sync status reported by the API
+/// synced: the tip is the best header and no peer reported a higher tip,
+/// a light node has no tip and only needs the headers
+//////
+#[derive(Serialize, Debug)]
+pub struct SyncProgress {
+    pub tip_height: u128,
+    pub best_header_height: u128,
+    pub best_peer_height: u128,
+    pub blocks_in_flight: usize,
+    pub sync_peers: usize,
+    pub synced: bool,
+    pub light: bool,
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        SyncState::default()
+    }
+
+    /// Sync state of a light node following the watched addresses
+    pub fn new_light(watch_list: WatchList) -> Self {
+        SyncState {
+            light: Some(watch_list),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.light.is_some()
+    }
+
+    pub fn add_peer(&mut self, peer: &peer::Handle) {
+        self.peers.insert(*peer.addr(), peer.clone());
+    }
+
+    pub fn set_peer_height(&mut self, peer: &peer::Handle, height: u128) {
+        self.peer_heights.insert(*peer.addr(), height);
+    }
+
+    pub fn progress(&self, blockchain: &Blockchain) -> SyncProgress {
+        let tip_height = blockchain.longest;
+        let best_header_height = blockchain.length[&blockchain.best_header];
+        let best_peer_height = self.peer_heights.values().cloned().max().unwrap_or(0);
+        SyncProgress {
+            tip_height,
+            best_header_height,
+            best_peer_height,
+            blocks_in_flight: self.in_flight.len(),
+            sync_peers: self.peers.len(),
+            synced: (self.is_light() || blockchain.tip() == blockchain.best_header)
+                && best_header_height >= best_peer_height,
+            light: self.is_light(),
+        }
+    }
+
+    /// A body arrived, from whichever peer
+    pub fn received(&mut self, block_hash: &H256) {
+        self.in_flight.remove(block_hash);
+    }
+
+    /// Ask the missing bodies of the best header chain, lowest heights first.
+    /// Requests that timed out are dropped together with their peer, and asked again elsewhere.
+    /// is_buffered tells if a body was already received and waits for its parent.
+    pub fn request_bodies<F>(&mut self, blockchain: &Blockchain, is_buffered: F)
+    where
+        F: Fn(&H256) -> bool,
+    {
+        // a light node never downloads bodies
+        if self.is_light() {
+            return;
+        }
+        let now = Instant::now();
+        let peers = &mut self.peers;
+        self.in_flight.retain(|_, (addr, asked)| {
+            if now.duration_since(*asked) < REQUEST_TIMEOUT {
+                return true;
+            }
+            peers.remove(addr);
+            false
+        });
+        if self.peers.is_empty() {
+            return;
+        }
+        let room = MAX_IN_FLIGHT.saturating_sub(self.in_flight.len());
+        let wanted: Vec<H256> = blockchain
+            .missing_bodies(DOWNLOAD_WINDOW)
+            .into_iter()
+            .filter(|hash| !self.in_flight.contains_key(hash) && !is_buffered(hash))
+            .take(room)
+            .collect();
+
+        // spread the batches over the peers in turn
+        let mut peers: Vec<(&SocketAddr, &mut peer::Handle)> = self.peers.iter_mut().collect();
+        peers.sort_by_key(|(addr, _)| **addr);
+        for batch in wanted.chunks(BATCH_SIZE) {
+            let peer_idx = self.next_peer % peers.len();
+            let (addr, peer) = &mut peers[peer_idx];
+            self.next_peer = self.next_peer.wrapping_add(1);
+            for hash in batch {
+                self.in_flight.insert(*hash, (**addr, now));
+            }
+            peer.write(Message::GetBlocks(batch.to_vec()));
+        }
+    }
+}
@@ -1,28 +1,41 @@
+use super::light::MAX_TXS_PER_ADDRESS;
 use super::message::Message;
+use super::orphan::OrphanPool;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::sync::{SyncState, MAX_HEADERS};
 use crate::mempool::Mempool;
 use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
 use crate::types::hash::H256;
 use crate::types::state::BlockToStateMap;
+use crate::types::state::BlockUndo;
 use crate::types::state::State;
 use crate::types::transaction::verify;
 use crate::types::transaction::SignedTransaction;
 
 use ring::digest;
 
+use crate::blockchain::consensus::ConsensusParams;
+use crate::blockchain::spv::TxProof;
+use crate::blockchain::validation::BlockError;
+use crate::blockchain::TipChange;
 use crate::Blockchain;
 use log::{debug, error, warn};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashSet, VecDeque};
+use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 #[cfg(any(test, test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test, test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
 use std::thread;
+// how often body requests are checked for timeouts when no Blocks or Headers arrive
+const SYNC_TICK: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
@@ -30,9 +43,10 @@ pub struct Worker {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     tx_mempool: Arc<Mutex<Mempool>>,
-    orphan_buffer: Arc<Mutex<HashMap<H256, Block>>>,
+    orphan_buffer: Arc<Mutex<OrphanPool>>,
     state: Arc<Mutex<State>>,
     bts_map: Arc<Mutex<BlockToStateMap>>,
+    sync: Arc<Mutex<SyncState>>,
 }
 
 impl Worker {
@@ -42,9 +56,10 @@ impl Worker {
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         tx_mempool: &Arc<Mutex<Mempool>>,
-        orphan_buffer: &Arc<Mutex<HashMap<H256, Block>>>,
+        orphan_buffer: &Arc<Mutex<OrphanPool>>,
         state: &Arc<Mutex<State>>,
         bts_map: &Arc<Mutex<BlockToStateMap>>,
+        sync: &Arc<Mutex<SyncState>>,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -55,6 +70,7 @@ impl Worker {
             orphan_buffer: Arc::clone(&orphan_buffer),
             state: Arc::clone(&state),
             bts_map: Arc::clone(&bts_map),
+            sync: Arc::clone(sync),
         }
     }
 
@@ -67,6 +83,18 @@ impl Worker {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::spawn(move || loop {
+            thread::sleep(SYNC_TICK);
+            self.sync_tick();
+        });
+    }
+
+    /// Ask again the bodies whose request timed out, even when no peer is sending anything
+    fn sync_tick(&self) {
+        let blockchain_with_lock = self.blockchain.lock().unwrap();
+        let orphan_buffer = self.orphan_buffer.lock().unwrap();
+        let mut sync = self.sync.lock().unwrap();
+        sync.request_bodies(&blockchain_with_lock, |hash| orphan_buffer.contains(hash));
     }
 
     fn worker_loop(&self) {
@@ -84,6 +112,7 @@ impl Worker {
             let mut state_with_lock = self.state.lock().unwrap();
             let mut orphan_buffer = self.orphan_buffer.lock().unwrap();
             let mut bts_map_with_lock = self.bts_map.lock().unwrap();
+            let mut sync = self.sync.lock().unwrap();
 
             match msg {
                 Message::Ping(nonce) => {
@@ -93,6 +122,22 @@ impl Worker {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
+                // a light node only follows the headers of new blocks
+                Message::NewBlockHashes(recv_new_hashes) if sync.is_light() => {
+                    for recv_hash in recv_new_hashes {
+                        if !blockchain_with_lock.headers.contains_key(&recv_hash) {
+                            peer.write(Message::GetHeaders(
+                                blockchain_with_lock.locator(),
+                                recv_hash,
+                            ));
+                        }
+                    }
+                }
+                // a light node has no state to check blocks and transactions against
+                Message::Blocks(_)
+                | Message::NewTransactionHashes(_)
+                | Message::Transactions(_)
+                    if sync.is_light() => {}
                 // receiving NewBlockHashes message mean that peer have new blocks to serve
                 Message::NewBlockHashes(recv_new_hashes) => {
                     println!("Receive NewBlockHashes message");
@@ -100,7 +145,7 @@ impl Worker {
                     for recv_hash in recv_new_hashes {
                         // if block already exists in either blockchain or orphan_buffer, skip
                         if blockchain_with_lock.blockchain.contains_key(&recv_hash)
-                            || orphan_buffer.contains_key(&recv_hash)
+                            || orphan_buffer.contains(&recv_hash)
                         {
                             continue;
                         }
@@ -118,8 +163,8 @@ impl Worker {
                                 [&missing_hash]
                                 .clone()]));
                         }
-                        if orphan_buffer.contains_key(&missing_hash) {
-                            peer.write(Message::Blocks(vec![orphan_buffer[&missing_hash].clone()]));
+                        if let Some(orphan_block) = orphan_buffer.get(&missing_hash) {
+                            peer.write(Message::Blocks(vec![orphan_block.clone()]));
                         }
                     }
                 }
@@ -127,8 +172,13 @@ impl Worker {
                 Message::Blocks(recv_blocks) => {
                     println!("new block received!");
                     let mut new_block_hashes: Vec<H256> = Vec::new();
-                    let mut get_blocks = Vec::new();
                     for block in recv_blocks {
+                        sync.received(&block.hash());
+                        // drop oversized blocks before they take space in the orphan buffer
+                        if let Err(e) = blockchain_with_lock.validate_block_limits(&block) {
+                            println!("fail block check: {}", e);
+                            continue;
+                        }
                         //if new block not in blockchain
                         if !blockchain_with_lock.blockchain.contains_key(&block.hash()) {
                             // if parent not in blockchain, then is orphan
@@ -137,125 +187,192 @@ impl Worker {
                                 .blockchain
                                 .contains_key(&block.header.parent)
                             {
-                                if !orphan_buffer.contains_key(&block.hash()) {
-                                    get_blocks.push(block.header.parent);
-                                    orphan_buffer.insert(block.header.parent, block);
+                                // its header can not be validated yet, but the work is checked
+                                // so buffering orphans costs a peer proof of work. The sender
+                                // picks the difficulty, the work must also meet pow_limit
+                                let target =
+                                    block.header.difficulty.min(blockchain_with_lock.pow_limit);
+                                if block.hash() > target {
+                                    println!("fail block check: {}", BlockError::BadProofOfWork);
+                                    continue;
                                 }
-                            } else {
-                                // if parent in block chain
-                                // if block hash smaller or equal to parent difficulty and not in blockchain, then proceed
-                                if block.hash()
-                                    <= blockchain_with_lock.blockchain[&block.header.parent]
-                                        .header
-                                        .difficulty
-                                {
-                                    let txs = block.clone().content.data;
-                                    //check all transactions in block are valid
-                                    if !is_block_tx_valid(txs.clone(), state_with_lock.clone()) {
-                                        continue;
-                                    }
-
-                                    // block received is valid, remove tx from mempool, update state
-                                    for tx in txs {
-                                        mempool_with_lock.remove(&tx);
-                                        state_with_lock.update(&tx);
-
-                                        // remove any double spend tx_in in mempool found in block
-                                        // mark tx_in in block as spent in spent_tx_in
-                                        for tx_in in tx.clone().transaction.tx_input {
-                                            if mempool_with_lock
-                                                .spent_tx_in
-                                                .contains_key(&(tx_in.previous_output, tx_in.index))
-                                            {
-                                                // remove tx in mempool using hash
-                                                let tx_hash = mempool_with_lock.spent_tx_in
-                                                    [&(tx_in.previous_output, tx_in.index)];
-                                                mempool_with_lock.remove_with_hash(tx_hash);
-                                            }
-                                            // add tx_in to spent_tx_in, mark as spent
-                                            mempool_with_lock.spent_tx_in.insert(
-                                                (tx_in.previous_output, tx_in.index),
-                                                tx.hash(),
-                                            );
-                                        }
+                                // a body we asked during sync arrived before its parent's,
+                                // otherwise we are missing headers: ask them up to this block,
+                                // and the parent body in case the peer's headers_after finds
+                                // nothing up to it on a side branch
+                                if !blockchain_with_lock.headers.contains_key(&block.hash()) {
+                                    peer.write(Message::GetHeaders(
+                                        blockchain_with_lock.locator(),
+                                        block.hash(),
+                                    ));
+                                    if !orphan_buffer.contains(&block.header.parent) {
+                                        peer.write(Message::GetBlocks(vec![block.header.parent]));
                                     }
+                                }
+                                if !orphan_buffer.contains(&block.hash()) {
+                                    blockchain_with_lock.stats.orphan_arrivals += 1;
+                                }
+                                orphan_buffer.insert(block, *peer.addr());
+                            } else if process_block(
+                                &block,
+                                &mut blockchain_with_lock,
+                                &mut mempool_with_lock,
+                                &mut state_with_lock,
+                                &mut bts_map_with_lock,
+                            )
+                            .is_ok()
+                            {
+                                new_block_hashes.push(block.hash());
+                                println!("new block inserted!");
 
-                                    //insert into block-to-state-map
-                                    bts_map_with_lock.insert(block.hash(), state_with_lock.clone());
-                                    // insert into blockchain
-                                    blockchain_with_lock.insert(&block);
-                                    new_block_hashes.push(block.hash());
-                                    println!("new block inserted!");
-
-                                    //if current block is orphan_buffer block's parent
-                                    let mut queue: VecDeque<H256> = VecDeque::new();
-                                    // bfs
-                                    queue.push_back(block.hash());
-                                    while !queue.is_empty() {
-                                        let cur_hash = queue.pop_front().unwrap();
-                                        let mut orphans_with_parent = Vec::new();
-
-                                        for (hash, orphan_block) in orphan_buffer.iter() {
-                                            if orphan_block.header.parent == cur_hash {
-                                                let txs = orphan_block.clone().content.data;
-
-                                                //remove tx from mempool
-                                                for tx in txs {
-                                                    mempool_with_lock.remove(&tx);
-                                                    state_with_lock.update(&tx);
-                                                    //remove any double spend tx_in in mempool found in block
-                                                    //add tx_in in block to spent_tx_in
-                                                    for tx_in in tx.clone().transaction.tx_input {
-                                                        if mempool_with_lock
-                                                            .spent_tx_in
-                                                            .contains_key(&(
-                                                                tx_in.previous_output,
-                                                                tx_in.index,
-                                                            ))
-                                                        {
-                                                            // remove tx in mempool using hash
-                                                            let tx_hash = mempool_with_lock
-                                                                .spent_tx_in[&(
-                                                                tx_in.previous_output,
-                                                                tx_in.index,
-                                                            )];
-                                                            mempool_with_lock
-                                                                .remove_with_hash(tx_hash);
-                                                        }
-                                                        // add tx_in to spent_tx_in
-                                                        mempool_with_lock.spent_tx_in.insert(
-                                                            (tx_in.previous_output, tx_in.index),
-                                                            tx.hash(),
-                                                        );
-                                                    }
-                                                }
-                                                //insert into block-to-state-map
-                                                bts_map_with_lock
-                                                    .insert(block.hash(), state_with_lock.clone());
-                                                // insert into blockchain
-                                                blockchain_with_lock.insert(&orphan_block);
-
-                                                orphans_with_parent.push(hash.clone());
-
-                                                queue.push_back(hash.clone())
-                                            }
-                                        }
-                                        for inserted_block in orphans_with_parent {
-                                            orphan_buffer.remove(&inserted_block);
+                                //if current block is orphan_buffer block's parent
+                                let mut queue: VecDeque<H256> = VecDeque::new();
+                                // bfs
+                                queue.push_back(block.hash());
+                                while let Some(cur_hash) = queue.pop_front() {
+                                    for orphan_block in orphan_buffer.remove_children(&cur_hash) {
+                                        if process_block(
+                                            &orphan_block,
+                                            &mut blockchain_with_lock,
+                                            &mut mempool_with_lock,
+                                            &mut state_with_lock,
+                                            &mut bts_map_with_lock,
+                                        )
+                                        .is_ok()
+                                        {
+                                            new_block_hashes.push(orphan_block.hash());
+                                            queue.push_back(orphan_block.hash());
                                         }
                                     }
                                 }
                             }
                         }
                     }
-                    if get_blocks.len() != 0 {
-                        self.server.broadcast(Message::GetBlocks(get_blocks));
-                    }
+                    // keep the body download going while the tip is behind the best header
+                    sync.request_bodies(&blockchain_with_lock, |hash| orphan_buffer.contains(hash));
                     if new_block_hashes.len() != 0 {
                         self.server
                             .broadcast(Message::NewBlockHashes(new_block_hashes));
                     }
                 }
+                Message::GetHeaders(locator, stop) => {
+                    let headers = blockchain_with_lock.headers_after(&locator, &stop, MAX_HEADERS);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::GetStatus => {
+                    peer.write(Message::Status(
+                        blockchain_with_lock.tip(),
+                        blockchain_with_lock.longest,
+                    ));
+                }
+                // a peer whose tip we do not know may be ahead, start syncing headers from it
+                Message::Status(peer_tip, peer_height) => {
+                    println!("Peer {} at height {}", peer.addr(), peer_height);
+                    sync.set_peer_height(&peer, peer_height);
+                    // a peer dropped after a timed out request serves bodies again
+                    sync.add_peer(&peer);
+                    if !blockchain_with_lock.headers.contains_key(&peer_tip) {
+                        peer.write(Message::GetHeaders(
+                            blockchain_with_lock.locator(),
+                            H256::default(),
+                        ));
+                    }
+                }
+                Message::Headers(headers) => {
+                    println!("Receive {} headers", headers.len());
+                    let now = now_millis();
+                    for header in headers.iter() {
+                        if blockchain_with_lock.headers.contains_key(&header.hash()) {
+                            continue;
+                        }
+                        // headers come in order, the following ones can not be valid either
+                        if let Err(e) = blockchain_with_lock.validate_header(header, now) {
+                            println!("fail header check: {}", e);
+                            break;
+                        }
+                        blockchain_with_lock.insert_header(header);
+                    }
+                    // a full message means the peer has more headers
+                    if headers.len() >= MAX_HEADERS {
+                        peer.write(Message::GetHeaders(
+                            blockchain_with_lock.locator(),
+                            H256::default(),
+                        ));
+                    }
+                    sync.add_peer(&peer);
+                    sync.request_bodies(&blockchain_with_lock, |hash| orphan_buffer.contains(hash));
+                    // the headers may bring new txs of the watched addresses, start a new round
+                    if let Some(watch_list) = sync.light.as_mut() {
+                        watch_list.asked.clear();
+                        if !watch_list.watched.is_empty() {
+                            watch_list.asked_peers.insert(*peer.addr());
+                            peer.write(Message::GetAddressTxs(
+                                watch_list.watched.iter().cloned().collect(),
+                            ));
+                        }
+                    }
+                }
+                Message::GetMerkleProof(tx_hashes) => {
+                    let proofs: Vec<TxProof> = tx_hashes
+                        .iter()
+                        .filter_map(|tx_hash| blockchain_with_lock.tx_proof(tx_hash))
+                        .collect();
+                    if !proofs.is_empty() {
+                        peer.write(Message::MerkleProof(proofs));
+                    }
+                }
+                Message::GetAddressTxs(addresses) => {
+                    if !sync.is_light() {
+                        let history = &blockchain_with_lock.address_index.history;
+                        let address_txs: Vec<(Address, Vec<H256>)> = addresses
+                            .iter()
+                            .map(|address| {
+                                // the most recent txs, a light node keeps no more
+                                let tx_hashes = history
+                                    .get(address)
+                                    .map(|txs| {
+                                        let skip = txs.len().saturating_sub(MAX_TXS_PER_ADDRESS);
+                                        txs[skip..].iter().map(|(tx_hash, _)| *tx_hash).collect()
+                                    })
+                                    .unwrap_or_default();
+                                (*address, tx_hashes)
+                            })
+                            .collect();
+                        peer.write(Message::AddressTxs(address_txs));
+                    }
+                }
+                Message::AddressTxs(address_txs) => {
+                    if let Some(watch_list) = sync.light.as_mut() {
+                        if !watch_list.add_address_txs(peer.addr(), &address_txs) {
+                            println!("ignore address txs {} was not asked for", peer.addr());
+                        } else {
+                            let wanted = watch_list.wanted_proofs(&blockchain_with_lock);
+                            if !wanted.is_empty() {
+                                watch_list.asked.extend(wanted.iter().cloned());
+                                peer.write(Message::GetMerkleProof(wanted));
+                            }
+                        }
+                    }
+                }
+                Message::MerkleProof(proofs) if sync.is_light() => {
+                    let watch_list = sync.light.as_mut().unwrap();
+                    for proof in proofs.iter() {
+                        if watch_list.add_proof(&blockchain_with_lock, proof) {
+                            println!("watched tx {} proven", proof.tx_hash);
+                        }
+                    }
+                }
+                Message::MerkleProof(proofs) => {
+                    for proof in proofs.iter() {
+                        match blockchain_with_lock.verify_tx_proof(proof) {
+                            Ok(confirmations) => println!(
+                                "tx {} proven with {} confirmations",
+                                proof.tx_hash, confirmations
+                            ),
+                            Err(e) => println!("fail proof check of tx {}: {}", proof.tx_hash, e),
+                        }
+                    }
+                }
                 Message::NewTransactionHashes(recv_new_hashes) => {
                     let mut missing_txs: Vec<H256> = Vec::new();
                     for recv_tx_hash in recv_new_hashes {
@@ -287,7 +404,11 @@ impl Worker {
                     let mut new_tx_hashes: Vec<H256> = Vec::new();
                     for signed_tx in signed_txs {
                         // check is transaction valid
-                        if !transaction_check(signed_tx.clone(), state_with_lock.clone()) {
+                        if !transaction_check(
+                            &signed_tx,
+                            &state_with_lock,
+                            &blockchain_with_lock.params,
+                        ) {
                             continue;
                         }
 
@@ -311,19 +432,256 @@ impl Worker {
             std::mem::drop(orphan_buffer);
             std::mem::drop(state_with_lock);
             std::mem::drop(bts_map_with_lock);
+            std::mem::drop(sync);
         }
     }
 }
-pub fn is_block_tx_valid(signed_txs: Vec<SignedTransaction>, state_with_lock: State) -> bool {
-    for signed_tx in signed_txs.clone() {
-        if !transaction_check(signed_tx, state_with_lock.clone()) {
-            return false;
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+//////
+/// process_block validates a block whose parent is already in the blockchain, then inserts it.
+/// The transactions are checked against the state of the parent block rather than the live state,
+/// so blocks on side forks are validated correctly. The block's undo is recorded in bts_map,
+/// and if the block moves the tip, the live state is moved to the state of the new tip.
+/// returns the reason if the block is rejected
+//////
+pub fn process_block(
+    block: &Block,
+    blockchain: &mut Blockchain,
+    mempool: &mut Mempool,
+    state: &mut State,
+    bts_map: &mut BlockToStateMap,
+) -> Result<(), BlockError> {
+    let parent_hash = block.header.parent;
+    let now = now_millis();
+    // parent link, difficulty, proof of work, merkle root and timestamp
+    if let Err(e) = blockchain.validate_block(block, now) {
+        println!("fail block check: {}", e);
+        return Err(e);
+    }
+    //check all transactions in block are valid against the parent's state
+    // a block extending the tip is applied to the live state directly
+    let old_tip = blockchain.tip();
+    let extends_tip = parent_hash == old_tip;
+    let undo = if extends_tip {
+        apply_block_txs(&block.content.data, &blockchain.params, state)
+            .and_then(|undo| check_state_root(block, state, undo))
+    } else {
+        let mut parent_state = bts_map.state_at(blockchain, state, &parent_hash);
+        apply_block_txs(&block.content.data, &blockchain.params, &mut parent_state)
+            .and_then(|undo| check_state_root(block, &mut parent_state, undo))
+    };
+    let undo = match undo {
+        Ok(undo) => undo,
+        Err(e) => {
+            println!("fail block check: {}", e);
+            // validate_block matched the body to the header and refused malleated bodies, so
+            // the block itself is invalid and its header must not stay the best one
+            blockchain.insert_header(&block.header);
+            blockchain.mark_invalid(&block.hash());
+            return Err(e);
+        }
+    };
+
+    //insert into block-to-state-map
+    bts_map.insert(block.hash(), undo);
+    // insert into blockchain, move the live state and mempool if the tip moved
+    match blockchain.insert(block) {
+        Some(tip_change) => {
+            if !extends_tip {
+                bts_map.move_state(blockchain, state, &tip_change.old_tip, &tip_change.new_tip);
+            }
+            reorg_mempool(blockchain, mempool, state, &tip_change);
+        }
+        None => {
+            // the live state must stay at the tip
+            if extends_tip {
+                state.revert_undo(&bts_map.bts_map[&block.hash()]);
+            }
+            // the blockchain refused the block, it must not be reported as inserted
+            if !blockchain.blockchain.contains_key(&block.hash()) {
+                bts_map.bts_map.remove(&block.hash());
+                println!("fail block check: {}", BlockError::KnownInvalid);
+                return Err(BlockError::KnownInvalid);
+            }
         }
     }
-    true
+    Ok(())
+}
+
+//////
+/// ImportReport is what import_blocks did with the blocks of a chain file
+/// imported: blocks inserted into the blockchain
+/// known: blocks already in the blockchain, skipped
+/// rejected: hash and reason of the blocks failing validation
+//////
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub known: usize,
+    pub rejected: Vec<(H256, BlockError)>,
+}
+
+// print the import progress every this many blocks
+const IMPORT_PROGRESS_STEP: usize = 1000;
+
+/// Replay the blocks of a chain file through the checks of a block received in Message::Blocks:
+/// the size limits, then process_block. The file has parents before children, so a block whose
+/// parent is unknown is rejected instead of waiting in the orphan buffer.
+/// returns the report, or the error reading the file, blocks before it stay imported
+pub fn import_blocks<I>(
+    blocks: I,
+    blockchain: &mut Blockchain,
+    mempool: &mut Mempool,
+    state: &mut State,
+    bts_map: &mut BlockToStateMap,
+) -> io::Result<ImportReport>
+where
+    I: Iterator<Item = io::Result<Block>>,
+{
+    let mut report = ImportReport::default();
+    let mut next_progress = IMPORT_PROGRESS_STEP;
+    for block in blocks {
+        let block = block?;
+        let block_hash = block.hash();
+        if blockchain.blockchain.contains_key(&block_hash) {
+            report.known += 1;
+        } else {
+            match blockchain
+                .validate_block_limits(&block)
+                .and_then(|_| process_block(&block, blockchain, mempool, state, bts_map))
+            {
+                Ok(()) => report.imported += 1,
+                Err(e) => {
+                    println!("rejected block {}: {}", block_hash, e);
+                    report.rejected.push((block_hash, e));
+                }
+            }
+        }
+        if report.imported + report.known + report.rejected.len() >= next_progress {
+            println!(
+                "import: {} blocks imported, {} known, {} rejected, tip at height {}",
+                report.imported,
+                report.known,
+                report.rejected.len(),
+                blockchain.longest
+            );
+            next_progress += IMPORT_PROGRESS_STEP;
+        }
+    }
+    Ok(report)
+}
+
+//////
+/// reorg_mempool brings mempool in line with the new longest chain after a tip change.
+/// txs of connected blocks leave mempool, and mempool txs conflicting with them are evicted.
+/// txs of disconnected blocks release their spent tx_in and, if still valid against the
+/// state of the new tip and not included in the new branch, go back to mempool.
+//////
+pub fn reorg_mempool(
+    blockchain: &Blockchain,
+    mempool: &mut Mempool,
+    state: &State,
+    tip_change: &TipChange,
+) {
+    let (disconnected, connected) = blockchain.reorg_path(&tip_change.old_tip, &tip_change.new_tip);
+    // collect from the oldest disconnected block, so a tx comes back before txs depending on it
+    let mut disconnected_txs: Vec<SignedTransaction> = Vec::new();
+    for block_hash in disconnected.iter().rev() {
+        let block_txs = &blockchain.blockchain[block_hash].content.data;
+        mempool.unspend_block_txs(block_txs);
+        disconnected_txs.extend(block_txs.iter().cloned());
+    }
+    let mut connected_tx_hashes: HashSet<H256> = HashSet::new();
+    for block_hash in connected.iter() {
+        let block_txs = &blockchain.blockchain[block_hash].content.data;
+        mempool.remove_block_txs(block_txs);
+        connected_tx_hashes.extend(block_txs.iter().map(|tx| tx.hash()));
+    }
+    for tx in disconnected_txs.iter() {
+        if connected_tx_hashes.contains(&tx.hash())
+            || !transaction_check(tx, state, &blockchain.params)
+        {
+            continue;
+        }
+        if mempool.reinsert(tx) {
+            println!("tx {} re-injected into mempool", tx.hash());
+        }
+    }
+}
+
+/// Check that the state root in the header of block matches state, the state after the block.
+/// On mismatch the block is taken back out of state.
+pub fn check_state_root(
+    block: &Block,
+    state: &mut State,
+    undo: BlockUndo,
+) -> Result<BlockUndo, BlockError> {
+    if state.state_root() != block.header.state_root {
+        state.revert_undo(&undo);
+        return Err(BlockError::BadStateRoot);
+    }
+    Ok(undo)
+}
+
+/// Check the transactions of a block one after another and apply them to state,
+/// which must be the state of the block's parent.
+/// The first transaction must be the coinbase of the block's height, paying at most
+/// the block subsidy plus the fees of the other transactions.
+/// returns the undo of the block, or the error (with state left untouched) if any transaction is invalid
+pub fn apply_block_txs(
+    signed_txs: &[SignedTransaction],
+    params: &ConsensusParams,
+    state: &mut State,
+) -> Result<BlockUndo, BlockError> {
+    let height = state.height + 1;
+    let coinbase = match signed_txs.first() {
+        Some(coinbase) if coinbase.coinbase_height() == Some(height) => coinbase,
+        _ => return Err(BlockError::BadCoinbase),
+    };
+    let mut undo = BlockUndo::default();
+    let mut fees: u64 = 0;
+    for signed_tx in signed_txs[1..].iter() {
+        if !transaction_check(signed_tx, state, params) {
+            state.revert_txs(&undo);
+            return Err(BlockError::InvalidTransaction(signed_tx.hash()));
+        }
+        // transaction_check made sure the inputs exist and cover the outputs
+        fees = fees.saturating_add(state.fee(signed_tx).unwrap());
+        state.update(signed_tx, &mut undo);
+    }
+    let reward = coinbase
+        .transaction
+        .tx_output
+        .iter()
+        .try_fold(0u64, |sum, tx_out| sum.checked_add(tx_out.value));
+    match reward {
+        Some(reward) if reward <= params.block_subsidy(height).saturating_add(fees) => {}
+        _ => {
+            state.revert_txs(&undo);
+            return Err(BlockError::BadCoinbase);
+        }
+    }
+    state.update(coinbase, &mut undo);
+    state.height = height;
+    Ok(undo)
 }
 
-pub fn transaction_check(signed_tx: SignedTransaction, state_with_lock: State) -> bool {
+pub fn transaction_check(
+    signed_tx: &SignedTransaction,
+    state_with_lock: &State,
+    params: &ConsensusParams,
+) -> bool {
+    // a coinbase is only valid as the first transaction of a block
+    if signed_tx.is_coinbase() {
+        println!("fail tx check: coinbase outside of a block");
+        return false;
+    }
     //Verify digital signature of a transaction
     //Check if the transaction is signed correctly by the public key(s).
     if !verify(
@@ -334,9 +692,8 @@ pub fn transaction_check(signed_tx: SignedTransaction, state_with_lock: State) -
         println!("fail tx signature check");
         return false;
     }
-    let tx_ins = signed_tx.transaction.tx_input;
-    let mut input_amount: u64 = 0;
-    let owner_pk = signed_tx.public_key;
+    let tx_ins = &signed_tx.transaction.tx_input;
+    let owner_pk = &signed_tx.public_key;
     for tx_in in tx_ins {
         let pre_out = tx_in.previous_output;
         let index = tx_in.index;
@@ -345,6 +702,11 @@ pub fn transaction_check(signed_tx: SignedTransaction, state_with_lock: State) -
             println!("fail signature check: tx_in not exist, tx double spend");
             return false;
         }
+        // coinbase outputs can not be spent before they mature
+        if !state_with_lock.is_mature(&pre_out, params.coinbase_maturity) {
+            println!("fail maturity check: tx_in spends an immature coinbase");
+            return false;
+        }
         let temp = state_with_lock.utxo[&(pre_out, index)]; //find tx in state
         let pre_tx_recipient_address = temp.1;
         let owner_pk_hash: H256 = digest::digest(&digest::SHA256, owner_pk.as_ref()).into();
@@ -354,15 +716,9 @@ pub fn transaction_check(signed_tx: SignedTransaction, state_with_lock: State) -
             println!("fail signature check: owner of tx input doesn't match to previous tx output");
             return false;
         }
-        input_amount += temp.0;
-    }
-    let tx_outputs = signed_tx.transaction.tx_output;
-    let mut output_amount: u64 = 0;
-    for tx_out in tx_outputs {
-        output_amount += tx_out.value;
     }
     // Spending Check: check the values of inputs are not less than those of outputs.
-    if input_amount < output_amount {
+    if state_with_lock.fee(signed_tx).is_none() {
         println!("fail spending check: input less than output");
         return false;
     }
@@ -403,12 +759,12 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     }
     let blockchain = Arc::new(Mutex::new(blockchain));
     let tx_mempool = Arc::new(Mutex::new(Mempool::new()));
-    let orphan_buffer: HashMap<H256, Block> = HashMap::new();
-    let orphan_buffer = Arc::new(Mutex::new(orphan_buffer));
+    let orphan_buffer = Arc::new(Mutex::new(OrphanPool::new()));
     let state = State::new();
-    let state = Arc::new(Mutex::new(state));
     let bts_map = BlockToStateMap::new();
+    let state = Arc::new(Mutex::new(state));
     let bts_map = Arc::new(Mutex::new(bts_map));
+    let sync = Arc::new(Mutex::new(SyncState::new()));
 
     let worker = Worker::new(
         1,
@@ -419,6 +775,7 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
         &orphan_buffer,
         &state,
         &bts_map,
+        &sync,
     );
     worker.start();
     (test_msg_sender, server_receiver, hashes)
@@ -479,6 +836,327 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn request_orphan_parent() {
+        use crate::blockchain::chainspec::ChainSpec;
+        use crate::types::hash::generate_random_hash;
+
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let genesis_hash = *v.last().unwrap();
+        let pow_limit = ChainSpec::default().genesis_target;
+        // an orphan whose hash is above pow_limit is not buffered even if its own difficulty
+        // allows it, so its child asks for it
+        let mut bad_orphan = generate_random_block(&generate_random_hash());
+        bad_orphan.header.difficulty = [255u8; 32].into();
+        while bad_orphan.hash() <= pow_limit {
+            bad_orphan.header.nonce = bad_orphan.header.nonce.wrapping_add(1);
+        }
+        let mut orphan = generate_random_block(&bad_orphan.hash());
+        orphan.header.difficulty = pow_limit;
+        while orphan.hash() > pow_limit {
+            orphan.header.nonce = orphan.header.nonce.wrapping_add(1);
+        }
+        let mut peer_receiver =
+            test_msg_sender.send(Message::Blocks(vec![bad_orphan, orphan.clone()]));
+        if let Message::GetHeaders(locator, stop) = peer_receiver.recv() {
+            assert_eq!(locator, vec![genesis_hash]);
+            assert_eq!(stop, orphan.hash());
+        } else {
+            panic!();
+        }
+        if let Message::GetBlocks(hashes) = peer_receiver.recv() {
+            assert_eq!(hashes, vec![orphan.header.parent]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_status() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let genesis_hash = *v.last().unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::GetStatus);
+        if let Message::Status(tip, height) = peer_receiver.recv() {
+            assert_eq!((tip, height), (genesis_hash, 0));
+        } else {
+            panic!();
+        }
+        // a peer with an unknown tip is asked for headers after our genesis
+        let mut peer_receiver = test_msg_sender.send(Message::Status([3u8; 32].into(), 5));
+        if let Message::GetHeaders(locator, stop) = peer_receiver.recv() {
+            assert_eq!(locator, vec![genesis_hash]);
+            assert_eq!(stop, Default::default());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_merkle_proof() {
+        use crate::blockchain::chainspec::ChainSpec;
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let allocation_hash = ChainSpec::default().genesis_block().content.data[0].hash();
+        let mut peer_receiver = test_msg_sender.send(Message::GetMerkleProof(vec![
+            [3u8; 32].into(),
+            allocation_hash,
+        ]));
+        if let Message::MerkleProof(proofs) = peer_receiver.recv() {
+            assert_eq!(proofs.len(), 1);
+            assert_eq!(proofs[0].tx_hash, allocation_hash);
+            assert!(proofs[0].verify_branch());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    fn coinbase_reward_and_maturity() {
+        use super::{apply_block_txs, transaction_check, BlockError, ConsensusParams, State};
+        use crate::types::address::Address;
+        use crate::types::transaction::{signed_spend, SignedTransaction};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let ico_key = State::ico_key();
+        let miner_key =
+            Ed25519KeyPair::from_seed_unchecked(b"00000000000000000000000000000001").unwrap();
+        let miner_address = Address::address_from_public_key(*miner_key.public_key());
+        let params = ConsensusParams {
+            coinbase_maturity: 2,
+            ..Default::default()
+        };
+        let subsidy = params.block_subsidy(1);
+        let mut state = State::new();
+
+        // a block needs a coinbase for its height, paying at most subsidy + fees
+        assert_eq!(
+            apply_block_txs(&[], &params, &mut state).unwrap_err(),
+            BlockError::BadCoinbase
+        );
+        let wrong_height = SignedTransaction::new_coinbase(2, miner_address, subsidy);
+        assert_eq!(
+            apply_block_txs(&[wrong_height], &params, &mut state).unwrap_err(),
+            BlockError::BadCoinbase
+        );
+        let ico_output = state.utxo.keys().next().unwrap().0;
+        let ico_spend = signed_spend(&ico_key, ico_output, 0, 99990);
+        let too_much = SignedTransaction::new_coinbase(1, miner_address, subsidy + 11);
+        assert_eq!(
+            apply_block_txs(&[too_much, ico_spend.clone()], &params, &mut state).unwrap_err(),
+            BlockError::BadCoinbase
+        );
+        assert_eq!(state.height, 0);
+        assert_eq!(state.utxo.len(), 1);
+        let coinbase = SignedTransaction::new_coinbase(1, miner_address, subsidy + 10);
+        apply_block_txs(&[coinbase.clone(), ico_spend], &params, &mut state).unwrap();
+        assert_eq!(state.height, 1);
+        assert_eq!(state.utxo[&(coinbase.hash(), 0)].0, subsidy + 10);
+
+        // the coinbase of block 1 can be spent from block 3 on
+        let coinbase_spend = signed_spend(&miner_key, coinbase.hash(), 0, subsidy);
+        assert!(!transaction_check(&coinbase_spend, &state, &params));
+        let coinbase_2 = SignedTransaction::new_coinbase(2, miner_address, subsidy);
+        assert_eq!(
+            apply_block_txs(
+                &[coinbase_2.clone(), coinbase_spend.clone()],
+                &params,
+                &mut state
+            )
+            .unwrap_err(),
+            BlockError::InvalidTransaction(coinbase_spend.hash())
+        );
+        apply_block_txs(&[coinbase_2], &params, &mut state).unwrap();
+        assert!(transaction_check(&coinbase_spend, &state, &params));
+        assert!(!transaction_check(&coinbase, &state, &params));
+    }
+    #[test]
+    fn reject_malleated_body() {
+        use super::{process_block, BlockError, State};
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use crate::types::block::{generate_random_block, Block};
+        use crate::types::hash::H256;
+        use crate::types::merkle::MerkleTree;
+        use crate::types::state::BlockToStateMap;
+        use crate::types::transaction::SignedTransaction;
+
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let mut state = State::new();
+        let mut bts_map = BlockToStateMap::new();
+        // a valid block on parent: its coinbase and the state root after it, solved
+        let mine = |blockchain: &Blockchain, state: &State, parent: &H256| {
+            let height = blockchain.length[parent] + 1;
+            let mut block = generate_random_block(parent);
+            block.content.data = vec![SignedTransaction::new_coinbase(
+                height,
+                State::ico_address(),
+                blockchain.params.block_subsidy(height),
+            )];
+            block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+            let mut next_state = state.clone();
+            super::apply_block_txs(&block.content.data, &blockchain.params, &mut next_state)
+                .unwrap();
+            block.header.state_root = next_state.state_root();
+            block.header.difficulty = blockchain.next_difficulty(parent);
+            block.header.timestamp = blockchain.headers[parent].timestamp.max(1_000) + 1;
+            while block.hash() > block.header.difficulty {
+                block.header.nonce = block.header.nonce.wrapping_add(1);
+            }
+            block
+        };
+        let block = mine(&blockchain, &state, &blockchain.tip());
+
+        // the last tx repeated keeps the merkle root and the hash, the copy is refused without
+        // marking the genuine block invalid
+        let mut malleated: Block = block.clone();
+        malleated.content.data.push(block.content.data[0].clone());
+        assert_eq!(malleated.hash(), block.hash());
+        assert_eq!(
+            process_block(
+                &malleated,
+                &mut blockchain,
+                &mut mempool,
+                &mut state,
+                &mut bts_map
+            ),
+            Err(BlockError::BadMerkleRoot)
+        );
+        assert!(blockchain.invalid.is_empty());
+
+        // the genuine block and its child still connect
+        assert!(process_block(
+            &block,
+            &mut blockchain,
+            &mut mempool,
+            &mut state,
+            &mut bts_map
+        )
+        .is_ok());
+        let child = mine(&blockchain, &state, &block.hash());
+        assert!(process_block(
+            &child,
+            &mut blockchain,
+            &mut mempool,
+            &mut state,
+            &mut bts_map
+        )
+        .is_ok());
+        assert_eq!(blockchain.tip(), child.hash());
+
+        // a block already known to be invalid is refused
+        let other = mine(&blockchain, &state, &child.hash());
+        blockchain.insert_header(&other.header);
+        blockchain.mark_invalid(&other.hash());
+        assert_eq!(
+            process_block(
+                &other,
+                &mut blockchain,
+                &mut mempool,
+                &mut state,
+                &mut bts_map
+            ),
+            Err(BlockError::KnownInvalid)
+        );
+        assert_eq!(blockchain.tip(), child.hash());
+        assert_eq!(state.state_root(), child.header.state_root);
+    }
+
+    #[test]
+    fn import_chain_blocks() {
+        use super::{import_blocks, BlockError, State};
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use crate::types::block::generate_random_block;
+        use crate::types::merkle::MerkleTree;
+        use crate::types::state::BlockToStateMap;
+        use crate::types::transaction::SignedTransaction;
+        use std::io;
+
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let mut state = State::new();
+        let mut bts_map = BlockToStateMap::new();
+        let genesis = blockchain.blockchain[&blockchain.tip()].clone();
+
+        // a valid block 1: genesis difficulty, coinbase of height 1 and the state root after it
+        let mut block = generate_random_block(&genesis.hash());
+        let subsidy = blockchain.params.block_subsidy(1);
+        block.content.data = vec![SignedTransaction::new_coinbase(
+            1,
+            State::ico_address(),
+            subsidy,
+        )];
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        let mut next_state = state.clone();
+        super::apply_block_txs(&block.content.data, &blockchain.params, &mut next_state).unwrap();
+        block.header.state_root = next_state.state_root();
+        block.header.difficulty = genesis.header.difficulty;
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        let bad = generate_random_block(&block.hash());
+
+        let blocks = vec![genesis.clone(), block.clone(), bad.clone()];
+        let report = import_blocks(
+            blocks.into_iter().map(Ok),
+            &mut blockchain,
+            &mut mempool,
+            &mut state,
+            &mut bts_map,
+        )
+        .unwrap();
+        assert_eq!((report.imported, report.known), (1, 1));
+        assert_eq!(
+            report.rejected,
+            vec![(bad.hash(), BlockError::BadDifficulty)]
+        );
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(state.state_root(), block.header.state_root);
+
+        // a read error stops the import
+        let blocks = vec![
+            Err(io::Error::new(io::ErrorKind::InvalidData, "bad")),
+            Ok(genesis),
+        ];
+        assert!(import_blocks(
+            blocks.into_iter(),
+            &mut blockchain,
+            &mut mempool,
+            &mut state,
+            &mut bts_map,
+        )
+        .is_err());
+    }
+    #[test]
+    fn reject_bad_state_root() {
+        use super::{apply_block_txs, check_state_root, BlockError, ConsensusParams, State};
+        use crate::types::block::generate_random_block;
+        use crate::types::hash::generate_random_hash;
+        use crate::types::transaction::SignedTransaction;
+
+        let params = ConsensusParams::default();
+        let mut state = State::new();
+        let parent_root = state.state_root();
+        let coinbase =
+            SignedTransaction::new_coinbase(1, State::ico_address(), params.block_subsidy(1));
+        let mut block = generate_random_block(&generate_random_hash());
+        block.content.data = vec![coinbase];
+
+        // a wrong root is rejected and the block taken back out of the state
+        let undo = apply_block_txs(&block.content.data, &params, &mut state).unwrap();
+        let after_root = state.state_root();
+        assert_eq!(
+            check_state_root(&block, &mut state, undo).unwrap_err(),
+            BlockError::BadStateRoot
+        );
+        assert_eq!(state.state_root(), parent_root);
+        assert_eq!(state.height, 0);
+
+        block.header.state_root = after_root;
+        let undo = apply_block_txs(&block.content.data, &params, &mut state).unwrap();
+        assert!(check_state_root(&block, &mut state, undo).is_ok());
+        assert_eq!(state.state_root(), after_root);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
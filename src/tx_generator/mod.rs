@@ -38,6 +38,7 @@ pub struct Context {
     tx_mempool: Arc<Mutex<Mempool>>,
     tx_sender: Sender<SignedTransaction>,
     state: Arc<Mutex<State>>,
+    coinbase_maturity: u128,
 }
 
 #[derive(Clone)]
@@ -49,6 +50,7 @@ pub struct Handle {
 pub fn new(
     tx_mempool: &Arc<Mutex<Mempool>>,
     state: &Arc<Mutex<State>>,
+    coinbase_maturity: u128,
 ) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (tx_sender, tx_receiver) = unbounded();
@@ -59,6 +61,7 @@ pub fn new(
         tx_mempool: Arc::clone(tx_mempool),
         tx_sender: tx_sender,
         state: Arc::clone(state),
+        coinbase_maturity,
     };
 
     let handle = Handle {
@@ -154,9 +157,22 @@ impl Context {
             let mempool_with_lock = self.tx_mempool.lock().unwrap();
             let state_with_lock = self.state.lock().unwrap();
 
-            let new_tx =
-                generate_random_signed_transaction(&key_vec, &address_vec, state_with_lock.clone());
+            let new_tx = generate_random_signed_transaction(
+                &key_vec,
+                &address_vec,
+                state_with_lock.clone(),
+                self.coinbase_maturity,
+            );
             std::mem::drop(state_with_lock);
+            // none of our keys own a spendable utxo, e.g. the miner pays to another address
+            let new_tx = match new_tx {
+                Some(new_tx) => new_tx,
+                None => {
+                    std::mem::drop(mempool_with_lock);
+                    thread::sleep(time::Duration::from_millis(100));
+                    continue;
+                }
+            };
             let new_tx_hash = new_tx.hash();
             // 7. mempool.insert
             // send to worker, worker will put into mempool and broadcast
@@ -182,15 +198,22 @@ pub fn generate_random_signed_transaction(
     key_vec: &Vec<Ed25519KeyPair>,
     address_vec: &Vec<Address>,
     state: State,
-) -> SignedTransaction {
+    coinbase_maturity: u128,
+) -> Option<SignedTransaction> {
     // 1. select random utxo from state
-    // first change hashmap key to vector
+    // only utxos owned by our keys that can be spent in the next block
+    let spendable = |key: &(H256, u8)| {
+        address_vec.contains(&state.utxo[key].1) && state.is_mature(&key.0, coinbase_maturity)
+    };
     let state_keys = state
         .utxo
-        .clone()
         .keys()
         .cloned()
+        .filter(|key| spendable(key))
         .collect::<Vec<(H256, u8)>>();
+    if state_keys.is_empty() {
+        return None;
+    }
     let rand_utxo_key = state_keys[random_select(state_keys.len())];
     let rand_utxo_value = state.utxo[&rand_utxo_key];
 
@@ -200,7 +223,7 @@ pub fn generate_random_signed_transaction(
     let mut new_tx_outs: Vec<TxOut> = Vec::new();
     // 3. collect utxos belongs to the owner, if amount more than 1000, break
     let mut total_amount = 0;
-    for key in state.utxo.keys() {
+    for key in state_keys.iter() {
         if owner_address == state.utxo[key].1 {
             total_amount += state.utxo[key].0;
             let new_tx_in = TxIn {
@@ -260,11 +283,11 @@ pub fn generate_random_signed_transaction(
     let owner_pub_key = owner_key.public_key().as_ref().to_vec();
     let new_signature = sign(&new_tx, &owner_key).as_ref().to_vec();
 
-    SignedTransaction {
+    Some(SignedTransaction {
         transaction: new_tx,
         signature: new_signature,
         public_key: owner_pub_key,
-    }
+    })
 }
 pub fn random_select(vec_len: usize) -> usize {
     let step = Uniform::new(0, vec_len);
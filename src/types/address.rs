@@ -49,6 +49,16 @@ impl std::fmt::Debug for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut raw_address: [u8; 20] = [0; 20];
+        hex::decode_to_slice(s, &mut raw_address)?;
+        Ok(Address(raw_address))
+    }
+}
+
 impl Address {
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
         let hash: Vec<u8> = digest::digest(&digest::SHA256, bytes).as_ref().to_vec();
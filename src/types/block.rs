@@ -6,6 +6,7 @@ use ring::digest;
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
+// state_root commits to the utxo set after the block, see State::state_root
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
     pub parent: H256,
@@ -13,6 +14,7 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    pub state_root: H256,
 }
 
 impl Hashable for Header {
@@ -46,6 +48,11 @@ impl Block {
     pub fn get_difficulty(&self) -> H256 {
         self.header.difficulty
     }
+
+    /// Serialized size in bytes, as sent in a Blocks message
+    pub fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
 }
 
 #[cfg(any(test, test_utilities))]
@@ -69,6 +76,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
         difficulty: difficulty,
         timestamp: timestamp,
         merkle_root: merkle_root,
+        state_root: H256::default(),
     };
     let content = Content { data: transactions };
     Block {
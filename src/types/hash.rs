@@ -91,6 +91,16 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut raw_hash: [u8; 32] = [0; 32];
+        hex::decode_to_slice(s, &mut raw_hash)?;
+        Ok(H256(raw_hash))
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -116,6 +126,120 @@ impl H256 {
         let address: Address = last20.into();
         address
     }
+
+    /// Treat the hash as a big endian u256, multiply it by num then divide by den.
+    /// The result saturates at the maximum u256, used for scaling the difficulty target.
+    pub fn mul_div(&self, num: u64, den: u64) -> H256 {
+        let limbs = self.to_limbs();
+        // multiply, keeping the carry out of the highest limb as a fifth limb
+        let mut product = [0u64; 5];
+        let mut carry: u128 = 0;
+        for (i, limb) in limbs.iter().enumerate().rev() {
+            let cur = *limb as u128 * num as u128 + carry;
+            product[i + 1] = cur as u64;
+            carry = cur >> 64;
+        }
+        product[0] = carry as u64;
+        // long division by den, limb by limb
+        let mut quotient = [0u64; 5];
+        let mut remainder: u128 = 0;
+        for (q, limb) in quotient.iter_mut().zip(product.iter()) {
+            let cur = (remainder << 64) | *limb as u128;
+            *q = (cur / den as u128) as u64;
+            remainder = cur % den as u128;
+        }
+        if quotient[0] != 0 {
+            return [255u8; 32].into();
+        }
+        H256::from_limbs(&[quotient[1], quotient[2], quotient[3], quotient[4]])
+    }
+
+    /// Expected number of hashes to find a block hash under this target: 2^256 / (target + 1).
+    /// Computed as (~target / (target + 1)) + 1 so it fits in u256.
+    pub fn work(&self) -> H256 {
+        let target = self.to_limbs();
+        let not_target = [!target[0], !target[1], !target[2], !target[3]];
+        let (divisor, overflow) = limbs_add(&target, &[0, 0, 0, 1]);
+        if overflow {
+            // target is the maximum u256, one hash is enough
+            return H256::from_limbs(&[0, 0, 0, 1]);
+        }
+        // bitwise long division
+        let mut quotient = [0u64; 4];
+        let mut remainder = [0u64; 4];
+        for bit in 0..256 {
+            remainder = limbs_shl1(&remainder);
+            let limb = bit / 64;
+            let shift = 63 - bit % 64;
+            remainder[3] |= (not_target[limb] >> shift) & 1;
+            if remainder >= divisor {
+                remainder = limbs_sub(&remainder, &divisor);
+                quotient[limb] |= 1 << shift;
+            }
+        }
+        let (work, _) = limbs_add(&quotient, &[0, 0, 0, 1]);
+        H256::from_limbs(&work)
+    }
+
+    /// Add two hashes as big endian u256, saturating at the maximum u256
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        let (sum, overflow) = limbs_add(&self.to_limbs(), &other.to_limbs());
+        if overflow {
+            return [255u8; 32].into();
+        }
+        H256::from_limbs(&sum)
+    }
+
+    // four u64 limbs, most significant first
+    fn to_limbs(self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: &[u64; 4]) -> H256 {
+        let mut buffer = [0u8; 32];
+        for (i, limb) in limbs.iter().enumerate() {
+            buffer[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        buffer.into()
+    }
+}
+
+// limb helpers for u256 arithmetic, most significant limb first
+fn limbs_add(a: &[u64; 4], b: &[u64; 4]) -> ([u64; 4], bool) {
+    let mut sum = [0u64; 4];
+    let mut carry = false;
+    for i in (0..4).rev() {
+        let (s1, c1) = a[i].overflowing_add(b[i]);
+        let (s2, c2) = s1.overflowing_add(carry as u64);
+        sum[i] = s2;
+        carry = c1 || c2;
+    }
+    (sum, carry)
+}
+
+fn limbs_sub(a: &[u64; 4], b: &[u64; 4]) -> [u64; 4] {
+    let mut diff = [0u64; 4];
+    let mut borrow = false;
+    for i in (0..4).rev() {
+        let (d1, b1) = a[i].overflowing_sub(b[i]);
+        let (d2, b2) = d1.overflowing_sub(borrow as u64);
+        diff[i] = d2;
+        borrow = b1 || b2;
+    }
+    diff
+}
+
+fn limbs_shl1(a: &[u64; 4]) -> [u64; 4] {
+    [
+        (a[0] << 1) | (a[1] >> 63),
+        (a[1] << 1) | (a[2] >> 63),
+        (a[2] << 1) | (a[3] >> 63),
+        a[3] << 1,
+    ]
 }
 
 #[cfg(any(test, test_utilities))]
@@ -126,3 +250,43 @@ pub fn generate_random_hash() -> H256 {
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::H256;
+
+    #[test]
+    fn work_of_target() {
+        // target 2^240 - 1 needs 2^16 hashes on average
+        let mut target = [255u8; 32];
+        target[0] = 0;
+        target[1] = 0;
+        let work = H256::from(target).work();
+        let mut expected = [0u8; 32];
+        expected[29] = 1;
+        assert_eq!(work, expected.into());
+        // maximum target needs one hash
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(H256::from([255u8; 32]).work(), one.into());
+    }
+
+    #[test]
+    fn mul_div_and_add() {
+        let mut raw = [0u8; 32];
+        raw[2] = 3;
+        let mut expected = [0u8; 32];
+        expected[2] = 1;
+        expected[3] = 128;
+        // 3 * 2^232 / 2 = 1.5 * 2^232
+        assert_eq!(H256::from(raw).mul_div(1, 2), expected.into());
+        // saturate on overflow
+        assert_eq!(
+            H256::from([255u8; 32]).mul_div(2, 1),
+            H256::from([255u8; 32])
+        );
+        let mut sum = [0u8; 32];
+        sum[2] = 6;
+        assert_eq!(H256::from(raw).saturating_add(&raw.into()), sum.into());
+    }
+}
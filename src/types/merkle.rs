@@ -8,16 +8,6 @@ pub struct MerkleTree {
     leaf_size: usize,
 }
 
-fn get_height(leaf_num: usize) -> u32 {
-    let mut height = 0;
-    let mut leaf_num = leaf_num;
-    while leaf_num > 1 {
-        height += 1;
-        leaf_num /= 2;
-    }
-    return height;
-}
-
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self
     where
@@ -89,23 +79,20 @@ impl MerkleTree {
         self.tree[self.tree.len() - 1]
     }
 
-    fn construct_proof(&self, proof: &mut Vec<H256>, height: u32, index: usize) {
-        let mut cur_layer_index = index;
-        let mut offset = 0;
-        for i in 0..height {
-            let small_offset = (cur_layer_index - offset) / 2;
-            if cur_layer_index % 2 == 0 {
-                proof.push(self.tree[cur_layer_index + 1]);
-            } else {
-                proof.push(self.tree[cur_layer_index - 1]);
-            }
-
-            if i != 0 {
-                offset += 2usize.pow(height - i);
-            } else {
-                offset += self.leaf_size;
+    // the tree keeps the levels one after another from the leaves up, every level but the
+    // root is padded to an even length by repeating its last node
+    fn construct_proof(&self, proof: &mut Vec<H256>, index: usize) {
+        let mut level_start = 0;
+        let mut level_len = self.leaf_size;
+        let mut cur_index = index;
+        while level_len > 1 {
+            proof.push(self.tree[level_start + (cur_index ^ 1)]);
+            level_start += level_len;
+            cur_index /= 2;
+            level_len /= 2;
+            if level_len > 1 && level_len % 2 == 1 {
+                level_len += 1;
             }
-            cur_layer_index = offset + small_offset;
         }
     }
 
@@ -117,16 +104,32 @@ impl MerkleTree {
             return proof;
         }
 
-        let height = get_height(self.leaf_size);
-        self.construct_proof(&mut proof, height, index);
+        self.construct_proof(&mut proof, index);
         return proof;
     }
 }
 
+/// Length of the proof of any leaf in a tree of leaf_size leaves
+pub fn proof_len(leaf_size: usize) -> usize {
+    let mut level_len = leaf_size + leaf_size % 2;
+    let mut len = 0;
+    while level_len > 1 {
+        len += 1;
+        level_len /= 2;
+        if level_len > 1 && level_len % 2 == 1 {
+            level_len += 1;
+        }
+    }
+    len
+}
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 
-pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, _leaf_size: usize) -> bool {
+pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    if index >= leaf_size || proof.len() != proof_len(leaf_size) {
+        return false;
+    }
     let height = proof.len();
     //temp
     let mut cur_node = *datum;
@@ -197,6 +200,40 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn merkle_proof_any_size() {
+        for leaf_size in 1..40usize {
+            let input_data: Vec<H256> = (0..leaf_size)
+                .map(|i| {
+                    let mut raw = [0u8; 32];
+                    raw[0] = i as u8;
+                    raw.into()
+                })
+                .collect();
+            let merkle_tree = MerkleTree::new(&input_data);
+            for (i, datum) in input_data.iter().enumerate() {
+                let proof = merkle_tree.proof(i);
+                assert!(verify(
+                    &merkle_tree.root(),
+                    &datum.hash(),
+                    &proof,
+                    i,
+                    leaf_size
+                ));
+            }
+            // the proof must have the length of the tree
+            let mut proof = merkle_tree.proof(0);
+            proof.push(merkle_tree.root());
+            assert!(!verify(
+                &merkle_tree.root(),
+                &input_data[0].hash(),
+                &proof,
+                0,
+                leaf_size
+            ));
+        }
+    }
+
     #[test]
     fn merkle_root() {
         let input_data: Vec<H256> = gen_merkle_tree_data!();
@@ -1,6 +1,9 @@
+use crate::blockchain::chainspec::ChainSpec;
+use crate::blockchain::Blockchain;
 use crate::mempool::Mempool;
 use crate::types::address::Address;
 use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
 use ring::digest;
 use ring::signature::{self, KeyPair};
@@ -10,9 +13,11 @@ use std::collections::{HashMap, HashSet};
 //////
 /// State is for storing information about the current state,
 /// the state contains utxo, which is a hashmap of the (previous_out, index): (amount, recipient) key value pair.
-/// we initialize state, granting 100000 coin to "00000000000000000000000000000000"
+/// the initial state holds the allocations of the chain spec, by default 100000 coin to "00000000000000000000000000000000"
 /// when updating state, we remove previous used txin then add txout to the state.
 /// the initial state does not belong to any transaction.
+/// height is the height of the block this state is at, coinbase keeps the height of each coinbase tx
+/// so that coinbase maturity can be checked.
 //////
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -21,73 +26,368 @@ pub struct State {
     //key: (previous_out, index)
     //value: (amount, recipient)
     pub utxo: HashMap<(H256, u8), (u64, Address)>,
+    pub height: u128,
+    //key: coinbase tx hash
+    //value: height of the block containing it
+    pub coinbase: HashMap<H256, u128>,
+}
+//////
+/// UtxoEntry is a leaf of the state commitment: one utxo with its key
+/// coinbase_height: height of the block of the coinbase tx creating it, None for other txs,
+/// committed so the maturity of the output is part of the state
+//////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoEntry {
+    pub key: (H256, u8),
+    pub value: (u64, Address),
+    pub coinbase_height: Option<u128>,
 }
-impl State {
-    // ICO
-    pub fn new() -> Self {
-        let mut utxo = HashMap::new();
-        let bytes32 = [0u8; 32];
-        let tx_hash: H256 = bytes32.into();
-        let output_idx: u8 = 0;
-        let value: u64 = 100000;
 
+impl Hashable for UtxoEntry {
+    fn hash(&self) -> H256 {
+        let serialized = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &serialized).into()
+    }
+}
+
+impl State {
+    // the address of the key with seed "00000000000000000000000000000000"
+    pub fn ico_address() -> Address {
         let seed = *b"00000000000000000000000000000000";
         let key = signature::Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
 
         let public_key = key.public_key();
         let pb_hash: H256 = digest::digest(&digest::SHA256, public_key.as_ref()).into();
-        let recipient: Address = pb_hash.to_addr();
-        let init_key = (tx_hash, output_idx);
-        let init_val = (value, recipient);
-        utxo.insert(init_key, init_val);
-
-        println!(
-            "ICO completed. {:?} coins are granted to {:?}",
-            value, recipient
-        );
-        let state = State { utxo: utxo };
+        pb_hash.to_addr()
+    }
+
+    // the key of the ico address, so tests can spend the allocations
+    #[cfg(test)]
+    pub fn ico_key() -> signature::Ed25519KeyPair {
+        signature::Ed25519KeyPair::from_seed_unchecked(b"00000000000000000000000000000000").unwrap()
+    }
+
+    // ICO of the default chain spec
+    pub fn new() -> Self {
+        let state = ChainSpec::default().genesis_state();
         println!("{:?}", state);
-        return state;
-    }
-
-    pub fn update(&mut self, signed_tx: &SignedTransaction) {
-        // println!("Before state update");
-        // for (key, val) in self.utxo.iter() {
-        //     println!("key: {:?}, val: {:?}", key, val);
-        // }
-        let tx = signed_tx.transaction.clone();
-        let tx_inputs = tx.tx_input;
-        let tx_outputs = tx.tx_output;
-        for tx_in in tx_inputs {
+        state
+    }
+
+    // the fee of a tx: input amount minus output amount
+    // None if an input is not in utxo or the outputs exceed the inputs
+    pub fn fee(&self, signed_tx: &SignedTransaction) -> Option<u64> {
+        let mut input_amount: u64 = 0;
+        for tx_in in signed_tx.transaction.tx_input.iter() {
+            let (value, _) = self.utxo.get(&(tx_in.previous_output, tx_in.index))?;
+            input_amount = input_amount.checked_add(*value)?;
+        }
+        let mut output_amount: u64 = 0;
+        for tx_out in signed_tx.transaction.tx_output.iter() {
+            output_amount = output_amount.checked_add(tx_out.value)?;
+        }
+        input_amount.checked_sub(output_amount)
+    }
+
+    // the utxo entries sorted by key, the leaves of the state commitment
+    pub fn sorted_entries(&self) -> Vec<UtxoEntry> {
+        let mut entries: Vec<UtxoEntry> = self
+            .utxo
+            .iter()
+            .map(|(key, value)| UtxoEntry {
+                key: *key,
+                value: *value,
+                coinbase_height: self.coinbase.get(&key.0).cloned(),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.key);
+        entries
+    }
+
+    // commitment to the utxo set: merkle root of the entries sorted by key, so it does not
+    // depend on the order the utxo were added in
+    pub fn state_root(&self) -> H256 {
+        MerkleTree::new(&self.sorted_entries()).root()
+    }
+
+    // proof that the utxo key is in the state: (entry, position, number of entries, merkle branch)
+    pub fn utxo_proof(&self, key: &(H256, u8)) -> Option<(UtxoEntry, usize, usize, Vec<H256>)> {
+        let entries = self.sorted_entries();
+        let position = entries.binary_search_by_key(key, |entry| entry.key).ok()?;
+        let branch = MerkleTree::new(&entries).proof(position);
+        Some((entries[position].clone(), position, entries.len(), branch))
+    }
+
+    // whether the outputs of tx_hash can be spent in the next block,
+    // a coinbase output needs coinbase_maturity blocks on top of its block
+    pub fn is_mature(&self, tx_hash: &H256, coinbase_maturity: u128) -> bool {
+        match self.coinbase.get(tx_hash) {
+            Some(coinbase_height) => self.height + 1 >= coinbase_height + coinbase_maturity,
+            None => true,
+        }
+    }
+
+    // apply a tx of the next block: remove the used tx_in then add the tx_out,
+    // the change is recorded in undo so the block can be reverted later
+    pub fn update(&mut self, signed_tx: &SignedTransaction, undo: &mut BlockUndo) {
+        for tx_in in signed_tx.transaction.tx_input.iter() {
             let key = (tx_in.previous_output, tx_in.index);
-            self.utxo.remove(&key);
+            if let Some(value) = self.utxo.remove(&key) {
+                // an output created earlier in the same block never existed before the block
+                if undo.created.remove(&key).is_none() {
+                    undo.spent.insert(key, value);
+                }
+            }
+        }
+        let tx_hash = signed_tx.hash();
+        for (idx, tx_out) in signed_tx.transaction.tx_output.iter().enumerate() {
+            let key = (tx_hash, idx as u8);
+            let value = (tx_out.value, tx_out.recipient_addr);
+            self.utxo.insert(key, value);
+            undo.created.insert(key, value);
+        }
+        if signed_tx.is_coinbase() {
+            self.coinbase.insert(tx_hash, self.height + 1);
+            undo.coinbase = Some(tx_hash);
+        }
+    }
+
+    // move the state forward over a block
+    pub fn apply_undo(&mut self, undo: &BlockUndo) {
+        for key in undo.spent.keys() {
+            self.utxo.remove(key);
+        }
+        for (key, value) in undo.created.iter() {
+            self.utxo.insert(*key, *value);
+        }
+        self.height += 1;
+        if let Some(coinbase_hash) = undo.coinbase {
+            self.coinbase.insert(coinbase_hash, self.height);
+        }
+    }
+
+    // move the state back to before a block
+    pub fn revert_undo(&mut self, undo: &BlockUndo) {
+        self.revert_txs(undo);
+        self.height -= 1;
+    }
+
+    // take back txs applied with update, the height is not touched
+    // used when a block is found invalid in the middle of applying it
+    pub fn revert_txs(&mut self, undo: &BlockUndo) {
+        for key in undo.created.keys() {
+            self.utxo.remove(key);
         }
-        let mut idx = 0;
-        for tx_out in tx_outputs {
-            let tx_hash = signed_tx.hash();
-            self.utxo
-                .insert((tx_hash, idx), (tx_out.value, tx_out.recipient_addr));
-            idx += 1;
+        for (key, value) in undo.spent.iter() {
+            self.utxo.insert(*key, *value);
+        }
+        if let Some(coinbase_hash) = undo.coinbase {
+            self.coinbase.remove(&coinbase_hash);
         }
-        // println!("After state update");
-        // for (key, val) in self.utxo.iter() {
-        //     println!("key: {:?}, val: {:?}", key, val);
-        // }
     }
 }
+
+//////
+/// BlockUndo is the net change a block makes to the utxo
+/// spent: utxo that existed before the block and are consumed by it
+/// created: utxo that are added by the block and still unspent after it
+/// coinbase: hash of the block's coinbase tx
+//////
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlockUndo {
+    pub spent: HashMap<(H256, u8), (u64, Address)>,
+    pub created: HashMap<(H256, u8), (u64, Address)>,
+    pub coinbase: Option<H256>,
+}
+
 //////
-/// BlockToStateMap keeps track of screenshot of state at particular block hash.
-/// Which means that for each block, there is a related state. We use hashmap for fast retrieval.
+/// BlockToStateMap keeps track of the state at any block hash without storing a copy per block.
+/// For each block, we keep its BlockUndo. The live state is always the state at the blockchain tip,
+/// the state of another block is reconstructed by reverting blocks down to the fork point,
+/// then applying blocks up to the requested block.
 //////
 pub struct BlockToStateMap {
-    pub bts_map: HashMap<H256, State>,
+    pub bts_map: HashMap<H256, BlockUndo>,
 }
 impl BlockToStateMap {
     pub fn new() -> Self {
         let bts_map = HashMap::new();
         BlockToStateMap { bts_map: bts_map }
     }
-    pub fn insert(&mut self, block_hash: H256, state: State) {
-        self.bts_map.insert(block_hash, state);
+    pub fn insert(&mut self, block_hash: H256, undo: BlockUndo) {
+        self.bts_map.insert(block_hash, undo);
+    }
+    // move state from the state at block `from` to the state at block `to`
+    pub fn move_state(&self, blockchain: &Blockchain, state: &mut State, from: &H256, to: &H256) {
+        let (disconnected, connected) = blockchain.reorg_path(from, to);
+        for block_hash in disconnected.iter() {
+            state.revert_undo(&self.bts_map[block_hash]);
+        }
+        for block_hash in connected.iter() {
+            state.apply_undo(&self.bts_map[block_hash]);
+        }
+    }
+    // reconstruct the state at block_hash from the live state at the blockchain tip
+    pub fn state_at(
+        &self,
+        blockchain: &Blockchain,
+        live_state: &State,
+        block_hash: &H256,
+    ) -> State {
+        let mut state = live_state.clone();
+        self.move_state(blockchain, &mut state, &blockchain.tip(), block_hash);
+        state
+    }
+    // rebuild the undo of every block in blockchain by replaying blocks from genesis,
+    // used when the blockchain is loaded from disk. Blocks on disk were validated before being stored.
+    // returns the map and the live state at the blockchain tip
+    pub fn rebuild(blockchain: &Blockchain, genesis_state: &State) -> (Self, State) {
+        let mut bts_map = BlockToStateMap::new();
+        let mut state = genesis_state.clone();
+        let genesis_hash = blockchain.ancestor(&blockchain.tip(), 0);
+        let mut cur_hash = genesis_hash;
+        for block_hash in blockchain.all_blocks_by_height() {
+            if block_hash == genesis_hash {
+                continue;
+            }
+            let block = &blockchain.blockchain[&block_hash];
+            bts_map.move_state(blockchain, &mut state, &cur_hash, &block.header.parent);
+            let mut undo = BlockUndo::default();
+            for tx in block.content.data.iter() {
+                state.update(tx, &mut undo);
+            }
+            state.height += 1;
+            bts_map.insert(block_hash, undo);
+            cur_hash = block_hash;
+        }
+        bts_map.move_state(blockchain, &mut state, &cur_hash, &blockchain.tip());
+        (bts_map, state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::{Transaction, TxIn, TxOut};
+
+    fn spend(previous_output: H256, index: u8, value: u64) -> SignedTransaction {
+        SignedTransaction {
+            transaction: Transaction {
+                tx_input: vec![TxIn {
+                    previous_output,
+                    index,
+                }],
+                tx_output: vec![TxOut {
+                    recipient_addr: generate_random_address(),
+                    value,
+                }],
+            },
+            public_key: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    fn sorted_keys(state: &State) -> Vec<(H256, u8)> {
+        let mut keys: Vec<(H256, u8)> = state.utxo.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn state_at_other_branch() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let genesis_state = State::new();
+        let ico_key = *genesis_state.utxo.keys().next().unwrap();
+        let mut bts_map = BlockToStateMap::new();
+
+        // block a spends the ICO and becomes the tip, the live state follows it
+        let mut state = genesis_state.clone();
+        let block_a = generate_random_block(&genesis_hash);
+        let mut undo_a = BlockUndo::default();
+        state.update(&spend(ico_key.0, ico_key.1, 100000), &mut undo_a);
+        state.height += 1;
+        bts_map.insert(block_a.hash(), undo_a);
+        blockchain.insert(&block_a);
+
+        let a_keys = sorted_keys(&state);
+
+        // block b spends the ICO on another branch, then block c spends b's output
+        let mut b_state = bts_map.state_at(&blockchain, &state, &genesis_hash);
+        assert_eq!(sorted_keys(&b_state), sorted_keys(&genesis_state));
+        let block_b = generate_random_block(&genesis_hash);
+        let tx_b = spend(ico_key.0, ico_key.1, 60000);
+        let mut undo_b = BlockUndo::default();
+        b_state.update(&tx_b, &mut undo_b);
+        b_state.height += 1;
+        let b_keys = sorted_keys(&b_state);
+        bts_map.insert(block_b.hash(), undo_b);
+        // a and b have the same work, keep the live state at whichever is the tip
+        if let Some(tip_change) = blockchain.insert(&block_b) {
+            bts_map.move_state(
+                &blockchain,
+                &mut state,
+                &tip_change.old_tip,
+                &tip_change.new_tip,
+            );
+        }
+        let block_c = generate_random_block(&block_b.hash());
+        let mut undo_c = BlockUndo::default();
+        b_state.update(&spend(tx_b.hash(), 0, 60000), &mut undo_c);
+        b_state.height += 1;
+        bts_map.insert(block_c.hash(), undo_c);
+        let tip_change = blockchain.insert(&block_c).unwrap();
+        bts_map.move_state(
+            &blockchain,
+            &mut state,
+            &tip_change.old_tip,
+            &tip_change.new_tip,
+        );
+
+        // the live state is at c, reconstruct a and b from it
+        assert_eq!(sorted_keys(&state), sorted_keys(&b_state));
+        assert_eq!(
+            sorted_keys(&bts_map.state_at(&blockchain, &state, &block_a.hash())),
+            a_keys
+        );
+        assert_eq!(
+            sorted_keys(&bts_map.state_at(&blockchain, &state, &block_b.hash())),
+            b_keys
+        );
+    }
+
+    #[test]
+    fn state_root_and_utxo_proof() {
+        use crate::types::hash::generate_random_hash;
+        use crate::types::merkle::verify;
+        let entries: Vec<((H256, u8), (u64, Address))> = (0..7)
+            .map(|i| {
+                (
+                    (generate_random_hash(), i),
+                    (i as u64, generate_random_address()),
+                )
+            })
+            .collect();
+        let mut state = State::default();
+        let mut reversed = State::default();
+        for (key, value) in entries.iter() {
+            state.utxo.insert(*key, *value);
+        }
+        for (key, value) in entries.iter().rev() {
+            reversed.utxo.insert(*key, *value);
+        }
+        let root = state.state_root();
+        assert_eq!(root, reversed.state_root());
+
+        for (key, value) in entries.iter() {
+            let (entry, position, leaf_size, branch) = state.utxo_proof(key).unwrap();
+            assert_eq!(entry.value, *value);
+            assert!(verify(&root, &entry.hash(), &branch, position, leaf_size));
+        }
+        assert!(state.utxo_proof(&(generate_random_hash(), 0)).is_none());
+        reversed.utxo.remove(&entries[0].0);
+        assert_ne!(root, reversed.state_root());
     }
 }
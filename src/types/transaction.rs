@@ -5,6 +5,7 @@ use rand::Rng;
 use ring::digest;
 use ring::signature::{self, Ed25519KeyPair, Signature};
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct TxIn {
@@ -44,6 +45,56 @@ impl Hashable for SignedTransaction {
     }
 }
 
+/// A coinbase transaction has one tx_in with this index, its previous_output encodes the block height
+pub const COINBASE_INDEX: u8 = u8::MAX;
+
+impl SignedTransaction {
+    /// Create the coinbase transaction paying the block reward to recipient at the given height.
+    /// The height makes coinbase transactions of different blocks have different hashes.
+    pub fn new_coinbase(height: u128, recipient: Address, value: u64) -> Self {
+        let mut height_bytes = [0u8; 32];
+        height_bytes[16..32].copy_from_slice(&height.to_be_bytes());
+        let transaction = Transaction {
+            tx_input: vec![TxIn {
+                previous_output: height_bytes.into(),
+                index: COINBASE_INDEX,
+            }],
+            tx_output: vec![TxOut {
+                recipient_addr: recipient,
+                value,
+            }],
+        };
+        SignedTransaction {
+            transaction,
+            public_key: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    /// Serialized size in bytes, the space the transaction takes in a block
+    pub fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+
+    /// A coinbase is not signed, and has a single tx_in with COINBASE_INDEX
+    pub fn is_coinbase(&self) -> bool {
+        self.public_key.is_empty()
+            && self.transaction.tx_input.len() == 1
+            && self.transaction.tx_input[0].index == COINBASE_INDEX
+    }
+
+    /// The block height encoded in a coinbase, None for other transactions
+    pub fn coinbase_height(&self) -> Option<u128> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let height_bytes: [u8; 32] = self.transaction.tx_input[0].previous_output.into();
+        Some(u128::from_be_bytes(
+            height_bytes[16..32].try_into().unwrap(),
+        ))
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let msg: Vec<u8> = bincode::serialize(&t).unwrap();
@@ -75,6 +126,33 @@ pub fn generate_random_transaction() -> Transaction {
         tx_output: output,
     }
 }
+
+/// A tx spending output index of previous_output to the address of key, signed with key
+#[cfg(test)]
+pub fn signed_spend(
+    key: &Ed25519KeyPair,
+    previous_output: H256,
+    index: u8,
+    value: u64,
+) -> SignedTransaction {
+    use ring::signature::KeyPair;
+
+    let transaction = Transaction {
+        tx_input: vec![TxIn {
+            previous_output,
+            index,
+        }],
+        tx_output: vec![TxOut {
+            recipient_addr: Address::address_from_public_key(*key.public_key()),
+            value,
+        }],
+    };
+    SignedTransaction {
+        signature: sign(&transaction, key).as_ref().to_vec(),
+        public_key: key.public_key().as_ref().to_vec(),
+        transaction,
+    }
+}
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
     let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
@@ -107,6 +185,27 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+    #[test]
+    fn coinbase_height() {
+        let coinbase = SignedTransaction::new_coinbase(42, generate_random_address(), 50);
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.coinbase_height(), Some(42));
+        let other = SignedTransaction::new_coinbase(
+            43,
+            coinbase.transaction.tx_output[0].recipient_addr,
+            50,
+        );
+        assert_ne!(coinbase.hash(), other.hash());
+        let key = key_pair::random();
+        let t = generate_random_transaction();
+        let signed = SignedTransaction {
+            signature: sign(&t, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction: t,
+        };
+        assert!(!signed.is_coinbase());
+        assert_eq!(signed.coinbase_height(), None);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST